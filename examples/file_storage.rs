@@ -57,6 +57,8 @@ impl Packable for SetAdd {
 }
 
 impl Transaction<Set> for SetAdd {
+    type Output = ();
+
     fn key() -> TransactionKey {
         1
     }
@@ -79,6 +81,8 @@ impl Packable for SetRemove {
 }
 
 impl Transaction<Set> for SetRemove {
+    type Output = ();
+
     fn key() -> TransactionKey {
         2
     }
@@ -0,0 +1,248 @@
+//! Prints the contents of a `FileStorage` file: its header, the offset and length of the stored
+//! object and of each transaction, and the first invalid chunk, if any.
+//!
+//! Usage: `protium-inspect [--json] [--hex | --base64] [--max-chunk-size=<bytes>] <path>`
+//!        `protium-inspect --salvage [--max-chunk-size=<bytes>] <path>`
+//!
+//! Payloads are printed only if `--hex` or `--base64` is given. Chunks larger than 1 GiB are
+//! reported as invalid, unless the file was written with a larger maximum chunk size that is given
+//! by `--max-chunk-size`. The file is never modified, so it is safe to inspect a file that another
+//! process has open for writing.
+//!
+//! With `--salvage`, everything that can be read from a damaged file is written to a new file
+//! alongside it instead, and a report of what was skipped is printed; see `Salvage::recover`.
+
+extern crate protium;
+
+use protium::{ChunkFault, FileScan, FileStorageOptions, Record};
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::process;
+
+const USAGE: &str = "Usage: protium-inspect [--json] [--hex | --base64] [--max-chunk-size=<bytes>] \
+                     <path>
+       protium-inspect --salvage [--max-chunk-size=<bytes>] <path>";
+
+const BASE64_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum Payloads {
+    Omitted,
+    Hex,
+    Base64,
+}
+
+fn main() {
+    let mut json = false;
+    let mut salvage = false;
+    let mut payloads = Payloads::Omitted;
+    let mut options = FileStorageOptions::new();
+    let mut path = None;
+
+    for arg in env::args().skip(1) {
+        match &arg[..] {
+            "--json" => json = true,
+            "--salvage" => salvage = true,
+            "--hex" => payloads = Payloads::Hex,
+            "--base64" => payloads = Payloads::Base64,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            },
+            _ if arg.starts_with("--max-chunk-size=") => {
+                match arg["--max-chunk-size=".len()..].parse() {
+                    Ok(bytes) => options = options.max_chunk_size(bytes),
+                    Err(_) => {
+                        eprintln!("{}", USAGE);
+                        process::exit(2);
+                    },
+                }
+            },
+            _ if arg.starts_with('-') || path.is_some() => {
+                eprintln!("{}", USAGE);
+                process::exit(2);
+            },
+            _ => path = Some(arg),
+        }
+    }
+
+    let path = match path {
+        Some(ref path) if !salvage || (!json && payloads == Payloads::Omitted) => path.clone(),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        },
+    };
+
+    if let Err(err) = fs::metadata(&path) {
+        eprintln!("protium-inspect: {}: {}", path, err);
+        process::exit(1);
+    }
+
+    if salvage {
+        match options.salvage(&path) {
+            Ok(salvage) => print!("{}", salvage),
+            Err(err) => {
+                eprintln!("protium-inspect: {}: {}", path, err);
+                process::exit(1);
+            },
+        }
+        return;
+    }
+
+    let scan = match options.scan(&path) {
+        Ok(scan) => scan,
+        Err(err) => {
+            eprintln!("protium-inspect: {}: {}", path, err);
+            process::exit(1);
+        },
+    };
+
+    if json {
+        println!("{}", format_json(&path, &scan, payloads));
+    } else {
+        print!("{}", format_human(&path, &scan, payloads));
+    }
+}
+
+fn format_human(path: &str, scan: &FileScan, payloads: Payloads) -> String {
+    let mut out = String::new();
+    let version = scan.version.map_or("none".to_owned(), |version| version.to_string());
+    writeln!(out, "file:           {}", path).unwrap();
+    writeln!(out, "version:        {}", version).unwrap();
+    writeln!(out, "file bytes:     {}", scan.file_bytes).unwrap();
+    writeln!(out, "valid bytes:    {}", scan.valid_bytes).unwrap();
+
+    for record in &scan.records {
+        if let Record::Object(_, ref object) = *record {
+            writeln!(out, "snapshot bytes: {}", object.0.len()).unwrap();
+        }
+    }
+
+    writeln!(out).unwrap();
+    writeln!(out, "{:>12}  {:<12}  {:>10}  {:>10}", "OFFSET", "KIND", "KEY", "LENGTH").unwrap();
+
+    for record in &scan.records {
+        let (offset, kind, key, data) = match *record {
+            Record::Object(offset, ref object) => (offset, "object", "-".to_owned(), &object.0),
+            Record::Transaction(offset, ref transaction) => {
+                (offset, "transaction", transaction.0.to_string(), &transaction.1)
+            },
+        };
+
+        write!(out, "{:>12}  {:<12}  {:>10}  {:>10}", offset, kind, key, data.len()).unwrap();
+        if payloads != Payloads::Omitted {
+            write!(out, "  {}", encode_payload(data, payloads)).unwrap();
+        }
+        writeln!(out).unwrap();
+    }
+
+    if let Some(bad_chunk) = scan.bad_chunk {
+        writeln!(out, "{:>12}  {:<12}  {}", bad_chunk.offset, "bad chunk",
+                 fault_name(bad_chunk.fault)).unwrap();
+    }
+
+    out
+}
+
+fn format_json(path: &str, scan: &FileScan, payloads: Payloads) -> String {
+    let mut out = String::new();
+    let version = scan.version.map_or("null".to_owned(), |version| version.to_string());
+    write!(out, "{{\"path\":{},\"version\":{},\"file_bytes\":{},\"valid_bytes\":{}",
+           json_string(path), version, scan.file_bytes, scan.valid_bytes).unwrap();
+
+    let snapshot_bytes = scan.records.iter().filter_map(|record| match *record {
+        Record::Object(_, ref object) => Some(object.0.len().to_string()),
+        _ => None,
+    }).next().unwrap_or_else(|| "null".to_owned());
+    write!(out, ",\"snapshot_bytes\":{},\"records\":[", snapshot_bytes).unwrap();
+
+    for (i, record) in scan.records.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+
+        let data = match *record {
+            Record::Object(offset, ref object) => {
+                write!(out, "{{\"offset\":{},\"kind\":\"object\"", offset).unwrap();
+                &object.0
+            },
+            Record::Transaction(offset, ref transaction) => {
+                write!(out, "{{\"offset\":{},\"kind\":\"transaction\",\"key\":{}", offset,
+                       transaction.0).unwrap();
+                &transaction.1
+            },
+        };
+
+        write!(out, ",\"length\":{}", data.len()).unwrap();
+        if payloads != Payloads::Omitted {
+            write!(out, ",\"payload\":\"{}\"", encode_payload(data, payloads)).unwrap();
+        }
+        out.push('}');
+    }
+
+    out.push_str("],\"bad_chunk\":");
+    match scan.bad_chunk {
+        Some(bad_chunk) => {
+            write!(out, "{{\"offset\":{},\"fault\":\"{}\"}}", bad_chunk.offset,
+                   fault_name(bad_chunk.fault)).unwrap();
+        },
+        None => out.push_str("null"),
+    }
+
+    out.push('}');
+    out
+}
+
+fn fault_name(fault: ChunkFault) -> String {
+    match fault {
+        ChunkFault::Truncated => "truncated".to_owned(),
+        ChunkFault::Checksum => "checksum".to_owned(),
+        ChunkFault::Marker => "marker".to_owned(),
+        ChunkFault::TooLarge(length) => format!("too large ({} bytes)", length),
+        ChunkFault::Malformed => "malformed".to_owned(),
+    }
+}
+
+fn encode_payload(data: &[u8], payloads: Payloads) -> String {
+    match payloads {
+        Payloads::Hex => data.iter().map(|byte| format!("{:02x}", byte)).collect(),
+        _ => encode_base64(data),
+    }
+}
+
+fn encode_base64(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+
+    for group in data.chunks(3) {
+        let bits = group.iter().enumerate()
+            .fold(0u32, |bits, (i, &byte)| bits | (byte as u32) << (16 - 8 * i));
+
+        for i in 0..4 {
+            if i <= group.len() {
+                out.push(BASE64_ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+
+    out
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::from("\"");
+
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+    out
+}
@@ -0,0 +1,104 @@
+use std::time::Duration;
+
+/// A snapshot of the state of a storage's transaction log, used by a `CompactionPolicy` to decide
+/// whether the log should be compacted into a new stored object.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CompactionStats {
+    /// The number of transactions stored since the object was last stored.
+    pub transaction_count: u64,
+    /// The number of bytes occupied by the transactions stored since the object was last stored.
+    pub log_bytes: u64,
+    /// The number of bytes occupied by the last stored object.
+    pub snapshot_bytes: u64,
+    /// The time elapsed since the object was last stored, or since the storage was loaded if the
+    /// object has not been stored since.
+    pub elapsed: Duration,
+}
+
+/// A trait that decides when a storage should compact its transaction log by storing the object
+/// in its entirety.
+///
+/// The policy is consulted each time a transaction is about to be stored. If it returns `true`,
+/// the updated object is stored in place of the transaction.
+///
+/// Policies must be `Send`, so that a storage holding one can be moved to another thread. Any
+/// `Fn(&CompactionStats) -> bool + Send` closure is a `CompactionPolicy`.
+pub trait CompactionPolicy: Send {
+    /// Returns `true` if the storage described by `stats` should be compacted.
+    fn should_compact(&self, stats: &CompactionStats) -> bool;
+}
+
+impl<F: Fn(&CompactionStats) -> bool + Send> CompactionPolicy for F {
+    fn should_compact(&self, stats: &CompactionStats) -> bool {
+        self(stats)
+    }
+}
+
+/// Compacts once the log holds at least the given number of transactions.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TransactionLimit(pub u64);
+
+impl Default for TransactionLimit {
+    /// Compacts after every 16 transactions.
+    fn default() -> TransactionLimit {
+        TransactionLimit(16)
+    }
+}
+
+impl CompactionPolicy for TransactionLimit {
+    fn should_compact(&self, stats: &CompactionStats) -> bool {
+        stats.transaction_count >= self.0
+    }
+}
+
+/// Compacts once the log occupies at least the given number of bytes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LogSizeLimit(pub u64);
+
+impl CompactionPolicy for LogSizeLimit {
+    fn should_compact(&self, stats: &CompactionStats) -> bool {
+        stats.log_bytes >= self.0
+    }
+}
+
+/// Compacts once the log occupies at least the given multiple of the size of the stored object.
+///
+/// For example, `LogRatio(1.0)` compacts once the log is as large as the object itself, which
+/// bounds the storage to roughly twice the size of the object.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LogRatio(pub f64);
+
+impl CompactionPolicy for LogRatio {
+    fn should_compact(&self, stats: &CompactionStats) -> bool {
+        stats.log_bytes > 0 && stats.log_bytes as f64 >= self.0 * stats.snapshot_bytes as f64
+    }
+}
+
+/// Compacts once the given amount of time has passed since the object was last stored.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TimeLimit(pub Duration);
+
+impl CompactionPolicy for TimeLimit {
+    fn should_compact(&self, stats: &CompactionStats) -> bool {
+        stats.elapsed >= self.0
+    }
+}
+
+/// Never compacts, so the log grows until the object is explicitly stored.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Never;
+
+impl CompactionPolicy for Never {
+    fn should_compact(&self, _: &CompactionStats) -> bool {
+        false
+    }
+}
+
+/// Compacts as soon as any of its policies would.
+pub struct AnyOf(pub Vec<Box<CompactionPolicy>>);
+
+impl CompactionPolicy for AnyOf {
+    fn should_compact(&self, stats: &CompactionStats) -> bool {
+        self.0.iter().any(|policy| policy.should_compact(stats))
+    }
+}
@@ -0,0 +1,301 @@
+//! A harness that checks that a `FileStorage` recovers a consistent object however the system
+//! fails while a workload is being applied to it.
+//!
+//! `CrashTest` runs a workload of transactions once against a real `FileStorage`, recording the
+//! contents of its files after every step. From consecutive recordings, it reconstructs each write
+//! the storage made during the step, i.e. an append to the storage file, or a replacement of it
+//! that is written to the temporary file and then renamed. It then builds the files as they would
+//! be found after a power failure at every point of that write:
+//!
+//! * after every byte offset of the write, as if the rest never reached the disk; and
+//! * with each sector of the write left zeroed, as if the sectors reached the disk out of order.
+//!
+//! For every such crash point, the files are written to a fresh directory, opened with
+//! `Protium::new`, and the recovered object is compared with the object as committed before and
+//! after the step. Any other outcome, including an error, is reported as a `CrashFailure`.
+
+use super::{FileStorage, FileStorageOptions, Packable, Protium, Transaction, Transactions};
+use error::Error;
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use std::path::Path;
+
+/// The name of the storage file within each directory used by the harness.
+const STORAGE_NAME: &str = "storage";
+
+/// The name of the temporary file to which `FileStorage` writes a replacement storage file.
+const TEMP_NAME: &str = "storage~";
+
+/// The contents of each file in a directory, by name.
+type Files = BTreeMap<String, Vec<u8>>;
+
+/// A step of a workload, applied to the `Protium` object under test.
+type Step<T> = Box<FnMut(&mut Protium<T, FileStorage<T>>) -> Result<(), Error>>;
+
+/// A workload of transactions, and the options of the `FileStorage` it is applied to, to be
+/// checked for consistency at every crash point.
+pub struct CrashTest<T: Packable + Default> {
+    transactions: Box<Fn() -> Transactions<T>>,
+    options: Box<Fn() -> FileStorageOptions>,
+    steps: Vec<Step<T>>,
+    sector_size: usize,
+}
+
+impl<T: Packable + Default> CrashTest<T> {
+    /// Initialize with an empty workload. `transactions` is called to register the transaction
+    /// types of the workload each time the storage is opened.
+    pub fn new<F: Fn() -> Transactions<T> + 'static>(transactions: F) -> CrashTest<T> {
+        CrashTest {
+            transactions: Box::new(transactions),
+            options: Box::new(FileStorageOptions::new),
+            steps: vec![],
+            sector_size: 512,
+        }
+    }
+
+    /// Sets the options with which the storage is opened, both to apply the workload and to
+    /// recover from each crash point.
+    ///
+    /// Defaults to `FileStorageOptions::new`.
+    pub fn options<F: Fn() -> FileStorageOptions + 'static>(mut self, options: F)
+        -> CrashTest<T>
+    {
+        self.options = Box::new(options);
+        self
+    }
+
+    /// Sets the size, in bytes, of the sectors that a write may be torn into.
+    ///
+    /// Defaults to 512.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` is 0.
+    pub fn sector_size(mut self, bytes: usize) -> CrashTest<T> {
+        assert!(bytes > 0, "Sector size must not be 0");
+        self.sector_size = bytes;
+        self
+    }
+
+    /// Adds a step to the workload that applies `transaction`.
+    pub fn apply<R: Transaction<T> + 'static>(self, transaction: R) -> CrashTest<T> {
+        let mut transaction = Some(transaction);
+        self.step(move |protium| match transaction.take() {
+            Some(transaction) => protium.apply(transaction).map(|_| ()),
+            None => Ok(()),
+        })
+    }
+
+    /// Adds a step to the workload that does anything with the `Protium` object, e.g. commits a
+    /// batch of transactions. The step is only called once.
+    pub fn step<F>(mut self, step: F) -> CrashTest<T>
+        where F: FnMut(&mut Protium<T, FileStorage<T>>) -> Result<(), Error> + 'static
+    {
+        self.steps.push(Box::new(step));
+        self
+    }
+
+    /// Applies the workload, then checks every crash point. `directory` must be writable; the
+    /// harness uses two directories inside it, removing any that exist.
+    ///
+    /// Returns `Err` if applying the workload fails, or if an IO error occurs in `directory`.
+    /// Inconsistent recoveries are described by the report instead.
+    pub fn run<P: AsRef<Path>>(mut self, directory: P) -> Result<CrashReport, Error> {
+        let workload_path = directory.as_ref().join("workload");
+        let crash_path = directory.as_ref().join("crash");
+        try!(reset_directory(&workload_path));
+
+        // The first recording is of the directory before the storage is created.
+        let mut recordings = vec![try!(read_files(&workload_path))];
+        let mut committed = vec![];
+        {
+            let storage = try!((self.options)().open(workload_path.join(STORAGE_NAME)));
+            let mut protium = try!(Protium::new(storage, (self.transactions)()));
+            recordings.push(try!(read_files(&workload_path)));
+            committed.push(try!(pack(protium.object())));
+
+            for step in &mut self.steps {
+                try!(step(&mut protium));
+                recordings.push(try!(read_files(&workload_path)));
+                committed.push(try!(pack(protium.object())));
+            }
+        }
+
+        let mut report = CrashReport { crash_points: 0, failures: vec![] };
+
+        for (step, pair) in recordings.windows(2).enumerate() {
+            // The object as committed before the step. Nothing at all is committed before the
+            // storage is first created, which recovers the default object.
+            let before = if step == 0 { &committed[0] } else { &committed[step - 1] };
+            let after = &committed[step];
+
+            for (crash_point, files) in crash_points(&pair[0], &pair[1], self.sector_size) {
+                report.crash_points += 1;
+                try!(reset_directory(&crash_path));
+                try!(write_files(&crash_path, &files));
+
+                let outcome = self.recover(&crash_path);
+                let consistent = match outcome {
+                    Ok(ref packed) => packed == before || packed == after,
+                    Err(_) => false,
+                };
+
+                if !consistent {
+                    report.failures.push(CrashFailure {
+                        step: step,
+                        crash_point: crash_point,
+                        outcome: outcome,
+                    });
+                }
+            }
+        }
+
+        try!(remove_directory(&crash_path));
+        try!(remove_directory(&workload_path));
+        Ok(report)
+    }
+
+    /// Opens the storage in `directory` and returns the recovered object, packed.
+    fn recover(&self, directory: &Path) -> Result<Vec<u8>, Error> {
+        let storage = try!((self.options)().open(directory.join(STORAGE_NAME)));
+        let protium = try!(Protium::new(storage, (self.transactions)()));
+        pack(protium.object())
+    }
+}
+
+/// The outcome of `CrashTest::run`.
+#[derive(Debug)]
+pub struct CrashReport {
+    /// The number of crash points checked.
+    pub crash_points: u64,
+    /// The crash points from which the storage did not recover a consistent object.
+    pub failures: Vec<CrashFailure>,
+}
+
+impl CrashReport {
+    /// Returns `true` if the storage recovered a consistent object from every crash point.
+    pub fn is_consistent(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// A crash point from which the storage did not recover a consistent object.
+#[derive(Debug)]
+pub struct CrashFailure {
+    /// The step of the workload during which the system failed, counting from 1, or 0 if it
+    /// failed while the storage was first created.
+    pub step: usize,
+    /// A description of the files as they were left by the failure.
+    pub crash_point: String,
+    /// The recovered object, packed, or the error that prevented it from being recovered.
+    pub outcome: Result<Vec<u8>, Error>,
+}
+
+/// Returns every state in which the files could be found after a failure while `before` was
+/// being changed into `after`, along with a description of each.
+fn crash_points(before: &Files, after: &Files, sector_size: usize) -> Vec<(String, Files)> {
+    let mut result = vec![];
+
+    for (name, new) in after {
+        let (name, old) = match before.get(name) {
+            Some(old) if old == new => continue,
+            // An append to the file.
+            Some(old) if new.starts_with(old) => (name.as_str(), &old[..]),
+            // A replacement of the storage file, first written to the temporary file.
+            _ if name == STORAGE_NAME => (TEMP_NAME, &[][..]),
+            _ => (name.as_str(), &[][..]),
+        };
+
+        for (description, torn) in torn_writes(old, new, sector_size) {
+            let mut files = before.clone();
+            files.insert(name.to_owned(), torn);
+            result.push((format!("{}: {}", name, description), files));
+        }
+
+        if name == TEMP_NAME {
+            let mut files = before.clone();
+            files.insert(name.to_owned(), new.clone());
+            result.push((format!("{}: written, but not yet renamed", name), files));
+        }
+    }
+
+    result
+}
+
+/// Returns every state in which a file could be found after a failure while `new` was being
+/// written over `old`, its prefix, along with a description of each.
+fn torn_writes(old: &[u8], new: &[u8], sector_size: usize) -> Vec<(String, Vec<u8>)> {
+    let written = new.len() - old.len();
+    let mut result = vec![];
+
+    for length in 0..written {
+        let description = format!("{} of {} bytes written", length, written);
+        result.push((description, new[..old.len() + length].to_vec()));
+    }
+
+    let first_sector = old.len() / sector_size;
+    for sector in first_sector..new.len().div_ceil(sector_size) {
+        let start = cmp::max(sector * sector_size, old.len());
+        let end = cmp::min((sector + 1) * sector_size, new.len());
+        let mut torn = new.to_vec();
+
+        for byte in &mut torn[start..end] {
+            *byte = 0;
+        }
+
+        let description = format!("{} bytes written, with bytes {}..{} lost", written, start, end);
+        result.push((description, torn));
+    }
+
+    result
+}
+
+fn pack<T: Packable>(object: &T) -> Result<Vec<u8>, Error> {
+    object.pack().map_err(|_| Error::ObjectPack)
+}
+
+/// Removes the directory at `path` and everything in it, if it exists.
+fn remove_directory(path: &Path) -> Result<(), Error> {
+    match fs::remove_dir_all(path) {
+        Ok(()) => Ok(()),
+        Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Removes the directory at `path`, if it exists, then creates it empty.
+fn reset_directory(path: &Path) -> Result<(), Error> {
+    try!(remove_directory(path));
+    try!(fs::create_dir_all(path));
+    Ok(())
+}
+
+/// Reads every file in the directory at `path`, except lock files.
+fn read_files(path: &Path) -> Result<Files, Error> {
+    let mut files = BTreeMap::new();
+
+    for entry in try!(fs::read_dir(path)) {
+        let entry = try!(entry);
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        if !name.ends_with(".lock") {
+            let mut data = vec![];
+            try!(try!(File::open(entry.path())).read_to_end(&mut data));
+            files.insert(name, data);
+        }
+    }
+
+    Ok(files)
+}
+
+/// Writes each of `files` into the directory at `path`.
+fn write_files(path: &Path, files: &Files) -> Result<(), Error> {
+    for (name, data) in files {
+        try!(try!(File::create(path.join(name))).write_all(data));
+    }
+
+    Ok(())
+}
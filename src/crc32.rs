@@ -0,0 +1,50 @@
+//! A minimal implementation of the CRC-32 (IEEE 802.3) checksum, as used by zlib and gzip.
+
+const POLYNOMIAL: u32 = 0xedb88320;
+
+static TABLE: [u32; 256] = make_table();
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+
+    while i < 256 {
+        let mut value = i as u32;
+        let mut bit = 0;
+
+        while bit < 8 {
+            value = if value & 1 == 1 { (value >> 1) ^ POLYNOMIAL } else { value >> 1 };
+            bit += 1;
+        }
+
+        table[i] = value;
+        i += 1;
+    }
+
+    table
+}
+
+/// An incremental CRC-32 checksum.
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32(u32);
+
+impl Crc32 {
+    /// Initialize a checksum over no data.
+    pub fn new() -> Crc32 {
+        Crc32(0xffffffff)
+    }
+
+    /// Feeds `data` into the checksum.
+    pub fn update(mut self, data: &[u8]) -> Crc32 {
+        for &byte in data {
+            self.0 = TABLE[((self.0 ^ byte as u32) & 0xff) as usize] ^ (self.0 >> 8);
+        }
+
+        self
+    }
+
+    /// Returns the checksum of all data fed so far.
+    pub fn finish(self) -> u32 {
+        !self.0
+    }
+}
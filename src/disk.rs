@@ -0,0 +1,493 @@
+//! Helpers shared by the storage implementations that keep their data in files.
+//!
+//! Every file begins with a 16-byte header: the magic bytes `PROTIUM\0`, then the little-endian
+//! `u32` format version, then the little-endian `u32` format flags. The header is followed by a
+//! sequence of chunks. A chunk is laid out as the 4-byte `CHUNK_MARKER`, a little-endian `u64`
+//! payload length, a little-endian `u32` CRC-32 of the length and payload, then the payload itself.
+//! Before revision 3, chunks have no marker and their lengths are `u32`.
+//!
+//! Format revisions:
+//!
+//! 0. The original layout, which has no header, and whose chunks are a little-endian `u32` payload
+//!    length followed by the payload, with no checksum. A `FileStorage` file that does not begin
+//!    with the magic bytes is read as revision 0 if it begins with a complete chunk.
+//! 1. The initial revision with a header.
+//! 2. Adds batch chunks, which hold several transactions that are stored or discarded together.
+//! 3. Widens the lengths of chunks and of the transactions in a batch from `u32` to `u64`, so that
+//!    objects and transactions of 4 GiB or more can be stored, and begins every chunk with a
+//!    marker, so that the next chunk can be found after damaged data.
+
+use super::{BATCH_TRANSACTION_KEY, PackedTransaction, TransactionKey};
+use crc32::Crc32;
+use error::Error;
+use verify::ChunkFault;
+
+use byteorder::{ByteOrder, LittleEndian};
+use std::cmp;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// The bytes that begin every file written by a storage in this crate.
+pub const MAGIC: &[u8] = b"PROTIUM\0";
+
+/// The revision of the file format written by this version of the crate.
+pub const FORMAT_VERSION: u32 = 3;
+
+/// The earliest revision of the file format that can be recorded in a header.
+pub const MIN_FORMAT_VERSION: u32 = 1;
+
+/// The revision given to files written before the header was introduced, which begin directly
+/// with their first chunk and have no chunk checksums.
+pub const LEGACY_FORMAT_VERSION: u32 = 0;
+
+/// The header flags understood by this version of the crate. None are defined yet, so a file
+/// with any flag set was written by a newer revision and is rejected.
+pub const FORMAT_FLAGS: u32 = 0;
+
+/// The length of the magic bytes, format version and flags that begin every file.
+pub const HEADER_LENGTH: usize = 16;
+
+/// The earliest revision of the file format whose lengths are `u64` rather than `u32`.
+const WIDE_LENGTH_VERSION: u32 = 3;
+
+/// The earliest revision of the file format whose chunks begin with `CHUNK_MARKER`.
+const MARKER_VERSION: u32 = 3;
+
+/// The bytes that begin every chunk. The first byte never occurs in UTF-8 text, so the marker
+/// rarely occurs within payloads by chance.
+pub const CHUNK_MARKER: [u8; 4] = [0xf5, b'P', b'C', b'K'];
+
+/// The default largest chunk payload, in bytes, that a storage reads or writes.
+pub const DEFAULT_MAX_CHUNK_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// The kinds of inter-process lock that a storage can hold on its files.
+///
+/// The lock is advisory: it is taken on a separate lock file, and only excludes other storage
+/// objects linked to the same files. The lock file is never removed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LockMode {
+    /// Excludes every other storage linked to the same files. This is the only mode in which
+    /// the storage may be written to.
+    Exclusive,
+    /// Excludes only storage objects that hold an exclusive lock, so several processes may read
+    /// the same files at once. The storage is read-only: it never modifies any file, and
+    /// storing to it fails with `Error::ReadOnly`.
+    Shared,
+    /// Takes no lock at all, so the storage may be opened while another process is writing to
+    /// it. The storage is read-only, as with `Shared`, and does not even create the lock file.
+    Unlocked,
+}
+
+/// Reads and validates the header at the start of `file`, returning the format version of the
+/// file.
+///
+/// Returns `Ok(None)` if the file ends partway through a valid header, which happens only if the
+/// file was torn while first being written.
+pub fn read_header(mut file: &File) -> Result<Option<u32>, Error> {
+    try!(file.seek(SeekFrom::Start(0)));
+    let mut header = Vec::with_capacity(HEADER_LENGTH);
+    try!(file.take(HEADER_LENGTH as u64).read_to_end(&mut header));
+
+    if header.len() < HEADER_LENGTH {
+        let magic_length = cmp::min(header.len(), MAGIC.len());
+        return if header[..magic_length] == MAGIC[..magic_length] {
+            Ok(None)
+        } else {
+            Err(Error::InvalidHeader)
+        };
+    }
+
+    if &header[..MAGIC.len()] != MAGIC {
+        return Err(Error::InvalidHeader);
+    }
+
+    let version = LittleEndian::read_u32(&header[8..12]);
+    if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
+        return Err(Error::UnsupportedVersion(version));
+    }
+
+    let flags = LittleEndian::read_u32(&header[12..16]);
+    if flags & !FORMAT_FLAGS != 0 {
+        return Err(Error::UnsupportedFlags(flags));
+    }
+
+    Ok(Some(version))
+}
+
+/// Reads the header at the start of a `FileStorage` file like `read_header`, except that a file
+/// that does not begin with the magic bytes, but parses as chunks of the legacy layout right up to
+/// its end, is read as `LEGACY_FORMAT_VERSION`. Either way, the file is left positioned at its
+/// first chunk.
+pub fn read_storage_header(mut file: &File, max_length: u64) -> Result<Option<u32>, Error> {
+    match read_header(file) {
+        Err(Error::InvalidHeader) => (),
+        result => return result,
+    }
+
+    // There is no checksum to verify, so a file whose header alone is damaged could otherwise be
+    // taken for a legacy one and truncated. Every chunk must fit in the file, every transaction
+    // must hold at least its key, and the last chunk must end exactly where the file does.
+    let file_length = try!(file.metadata()).len();
+    let mut position = 0;
+    let mut length = [0; 4];
+
+    while position < file_length {
+        if file_length - position < 4 {
+            return Err(Error::InvalidHeader);
+        }
+
+        try!(file.seek(SeekFrom::Start(position)));
+        try!(file.read_exact(&mut length));
+
+        let length = LittleEndian::read_u32(&length) as u64;
+        if length > file_length - position - 4 || check_chunk_length(length, max_length).is_err() ||
+            (position > 0 && length < 4)
+        {
+            return Err(Error::InvalidHeader);
+        }
+
+        position += 4 + length;
+    }
+
+    try!(file.seek(SeekFrom::Start(0)));
+    Ok(Some(LEGACY_FORMAT_VERSION))
+}
+
+/// Returns the length of the header that begins a file of the given format version.
+pub fn header_length(version: u32) -> u64 {
+    if version == LEGACY_FORMAT_VERSION { 0 } else { HEADER_LENGTH as u64 }
+}
+
+/// The outcome of reading the chunk at the current position of a file.
+pub enum ChunkRead {
+    /// A valid chunk holding the payload.
+    Chunk(Vec<u8>),
+    /// The file ends exactly where the chunk would begin.
+    End,
+    /// The chunk is invalid, so it and everything after it are to be discarded.
+    Fault(ChunkFault),
+}
+
+/// Reads the chunk at the current position of `file`, reporting why it is invalid if it is.
+///
+/// Nothing is allocated for the payload until its length has been checked against the rest of
+/// the file and against `max_length`.
+pub fn scan_chunk(mut file: &File, version: u32, max_length: u64) -> Result<ChunkRead, Error> {
+    let position = try!(file.stream_position());
+    let remaining = try!(file.metadata()).len().saturating_sub(position);
+    let header_length = chunk_header_length(version);
+
+    if remaining == 0 {
+        return Ok(ChunkRead::End);
+    } else if remaining < header_length as u64 {
+        return Ok(ChunkRead::Fault(ChunkFault::Truncated));
+    }
+
+    let mut header = vec![0; header_length];
+    match file.read_exact(&mut header) {
+        Ok(()) => (),
+        Err(ref err) if err.kind() == ErrorKind::UnexpectedEof => {
+            return Ok(ChunkRead::Fault(ChunkFault::Truncated));
+        },
+        Err(err) => return Err(err.into()),
+    }
+
+    let length = match decode_chunk_header(&header, version) {
+        Ok(length) => length,
+        Err(fault) => return Ok(ChunkRead::Fault(fault)),
+    };
+
+    if length > remaining - header_length as u64 {
+        return Ok(ChunkRead::Fault(ChunkFault::Truncated));
+    } else if check_chunk_length(length, max_length).is_err() {
+        return Ok(ChunkRead::Fault(ChunkFault::TooLarge(length)));
+    }
+
+    let mut buf = Vec::with_capacity(length as usize);
+    let length_read = try!(file.take(length).read_to_end(&mut buf));
+
+    if length != length_read as u64 {
+        Ok(ChunkRead::Fault(ChunkFault::Truncated))
+    } else if !checksum_matches(&header, version, &buf) {
+        Ok(ChunkRead::Fault(ChunkFault::Checksum))
+    } else {
+        Ok(ChunkRead::Chunk(buf))
+    }
+}
+
+/// Returns the payload length recorded in `header`, the `chunk_header_length` bytes that begin a
+/// chunk in a file of the given format version.
+///
+/// Returns `Err(ChunkFault::Marker)` if the header does not begin with the chunk marker.
+pub fn decode_chunk_header(header: &[u8], version: u32) -> Result<u64, ChunkFault> {
+    let marker = marker_length(version);
+    if header[..marker] != CHUNK_MARKER[..marker] {
+        return Err(ChunkFault::Marker);
+    }
+
+    Ok(decode_length(&header[marker..marker + length_width(version)]))
+}
+
+/// Reads the chunk at the current position of `file`.
+///
+/// Returns `Ok(None)` if the chunk is truncated or fails its checksum, which includes a chunk
+/// whose length runs past the end of the file.
+///
+/// Returns `Err(Error::ChunkTooLarge)` if the payload fits in the file but is longer than
+/// `max_length`.
+pub fn read_chunk(file: &File, version: u32, max_length: u64) -> Result<Option<Vec<u8>>, Error> {
+    match try!(scan_chunk(file, version, max_length)) {
+        ChunkRead::Chunk(payload) => Ok(Some(payload)),
+        ChunkRead::Fault(ChunkFault::TooLarge(length)) => Err(Error::ChunkTooLarge(length)),
+        _ => Ok(None),
+    }
+}
+
+/// Returns the length of the marker, payload length and checksum that begin every chunk in a file
+/// of the given format version.
+pub fn chunk_header_length(version: u32) -> usize {
+    if version == LEGACY_FORMAT_VERSION {
+        length_width(version)
+    } else {
+        marker_length(version) + length_width(version) + 4
+    }
+}
+
+/// Returns the length of the transaction length that precedes each transaction in a batch in a
+/// file of the given format version.
+pub fn batch_entry_header_length(version: u32) -> usize {
+    length_width(version)
+}
+
+/// Returns `true` if every chunk in a file of the given format version begins with the chunk
+/// marker.
+pub fn has_chunk_marker(version: u32) -> bool {
+    version >= MARKER_VERSION
+}
+
+/// Returns the number of bytes taken by the chunk marker in a file of the given format version.
+fn marker_length(version: u32) -> usize {
+    if has_chunk_marker(version) { CHUNK_MARKER.len() } else { 0 }
+}
+
+/// Returns the number of bytes taken by a length in a file of the given format version.
+fn length_width(version: u32) -> usize {
+    if version < WIDE_LENGTH_VERSION { 4 } else { 8 }
+}
+
+/// Reads a little-endian length that is either 4 or 8 bytes wide.
+fn decode_length(bytes: &[u8]) -> u64 {
+    match bytes.len() {
+        4 => LittleEndian::read_u32(bytes) as u64,
+        _ => LittleEndian::read_u64(bytes),
+    }
+}
+
+/// Opens the lock file at `path` and takes a lock of the given kind on it.
+///
+/// Returns `Ok(None)` if no lock is to be taken.
+pub fn acquire_lock(path: &Path, mode: LockMode) -> Result<Option<File>, Error> {
+    if mode == LockMode::Unlocked {
+        return Ok(None);
+    }
+
+    let lock = try!(OpenOptions::new().read(true).write(true).create(true).truncate(false)
+        .open(path));
+    let locked = match mode {
+        LockMode::Shared => lock.try_lock_shared(),
+        _ => lock.try_lock(),
+    };
+
+    match locked {
+        Ok(()) => Ok(Some(lock)),
+        Err(TryLockError::WouldBlock) => Err(Error::Locked),
+        Err(TryLockError::Error(err)) => Err(err.into()),
+    }
+}
+
+/// Returns `true` if the file at `path` holds a valid header followed by a valid object chunk, or
+/// holds nothing but an object chunk of the legacy layout.
+///
+/// An invalid header is reported as `Ok(false)` rather than as an error, since the sectors of a
+/// file that was being written when the system failed may reach the disk in any order.
+pub fn is_complete_object(path: &Path, max_length: u64) -> Result<bool, Error> {
+    let mut file = try!(File::open(path));
+
+    let version = match read_storage_header(&file, max_length) {
+        Ok(Some(version)) => version,
+        Ok(None) => return Ok(false),
+        Err(Error::InvalidHeader) |
+        Err(Error::UnsupportedVersion(_)) |
+        Err(Error::UnsupportedFlags(_)) => return Ok(false),
+        Err(err) => return Err(err),
+    };
+
+    if try!(read_chunk(&file, version, max_length)).is_none() {
+        return Ok(false);
+    }
+
+    // Without a checksum, a legacy object is only known to be complete if nothing follows it.
+    if version == LEGACY_FORMAT_VERSION {
+        return Ok(try!(file.stream_position()) == try!(file.metadata()).len());
+    }
+
+    Ok(true)
+}
+
+/// Syncs the data and metadata of the existing file at `path`.
+pub fn sync_file(path: &Path) -> Result<(), Error> {
+    try!(try!(File::open(path)).sync_all());
+    Ok(())
+}
+
+/// Returns `Err(Error::ChunkTooLarge)` if a chunk payload of `length` bytes is longer than
+/// `max_length`, or could not be held in memory on this platform, so that it is neither written
+/// nor read.
+pub fn check_chunk_length(length: u64, max_length: u64) -> Result<(), Error> {
+    if length > max_length || length > usize::MAX as u64 {
+        return Err(Error::ChunkTooLarge(length));
+    }
+
+    Ok(())
+}
+
+/// Durably records changes to the entries of the directory containing `path`, such as renames.
+#[cfg(unix)]
+pub fn sync_directory(path: &Path) -> Result<(), Error> {
+    let directory = match path.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+
+    try!(try!(File::open(directory)).sync_all());
+    Ok(())
+}
+
+/// Directories cannot be opened as files on this platform, so renames are left to be made durable
+/// by the file system.
+#[cfg(not(unix))]
+pub fn sync_directory(_: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Returns the payload of a chunk holding a transaction, which is the little-endian `u32`
+/// transaction key followed by the packed transaction.
+pub fn encode_transaction(key: TransactionKey, packed: &[u8]) -> Vec<u8> {
+    let mut payload = vec![0; 4];
+    LittleEndian::write_u32(&mut payload, key);
+    payload.extend(packed);
+    payload
+}
+
+/// Returns the payload of a chunk holding a batch of transactions, which is the little-endian
+/// `u32` key `BATCH_TRANSACTION_KEY` followed by each transaction in turn. Each transaction is laid
+/// out as a little-endian `u64` length of its key and packed data, the little-endian `u32`
+/// transaction key, then the packed transaction.
+pub fn encode_batch(transactions: &[PackedTransaction]) -> Vec<u8> {
+    let mut payload = vec![0; 4];
+    LittleEndian::write_u32(&mut payload, BATCH_TRANSACTION_KEY);
+
+    for transaction in transactions {
+        let mut buf = [0; 12];
+        LittleEndian::write_u64(&mut buf[0..8], (transaction.1.len() + 4) as u64);
+        LittleEndian::write_u32(&mut buf[8..12], transaction.0);
+        payload.extend(&buf);
+        payload.extend(&transaction.1);
+    }
+
+    payload
+}
+
+/// Returns the length of the payload that `encode_transaction` returns for a transaction packed
+/// into `packed_length` bytes.
+pub fn transaction_payload_length(packed_length: usize) -> usize {
+    4 + packed_length
+}
+
+/// Returns the length of the payload that `encode_batch` returns for `transactions`.
+pub fn batch_payload_length(transactions: &[PackedTransaction]) -> usize {
+    let entry_header_length = batch_entry_header_length(FORMAT_VERSION);
+    4 + transactions.iter()
+        .map(|transaction| entry_header_length + transaction_payload_length(transaction.1.len()))
+        .sum::<usize>()
+}
+
+/// Splits the payload of a chunk holding a transaction or a batch of transactions, read from a file
+/// of the given format version, into the individual transactions.
+///
+/// Returns `None` if the payload is malformed.
+pub fn decode_transactions(mut payload: Vec<u8>, version: u32)
+    -> Option<Vec<PackedTransaction>>
+{
+    if payload.len() < 4 {
+        return None;
+    }
+
+    let data = payload.split_off(4);
+    let key = LittleEndian::read_u32(&payload);
+
+    if key != BATCH_TRANSACTION_KEY {
+        return Some(vec![PackedTransaction(key, data)]);
+    }
+
+    let width = length_width(version);
+    let mut transactions = vec![];
+    let mut rest = &data[..];
+
+    while !rest.is_empty() {
+        if rest.len() < width + 4 {
+            return None;
+        }
+
+        let length = decode_length(&rest[..width]);
+        if length < 4 || ((rest.len() - width) as u64) < length {
+            return None;
+        }
+
+        let end = width + length as usize;
+        let key = LittleEndian::read_u32(&rest[width..width + 4]);
+        transactions.push(PackedTransaction(key, rest[width + 4..end].to_vec()));
+        rest = &rest[end..];
+    }
+
+    Some(transactions)
+}
+
+/// Returns the bytes of the header that begins every file written by this version.
+pub fn encode_header() -> Vec<u8> {
+    let mut header = vec![0; HEADER_LENGTH];
+    header[..MAGIC.len()].copy_from_slice(MAGIC);
+    LittleEndian::write_u32(&mut header[8..12], FORMAT_VERSION);
+    LittleEndian::write_u32(&mut header[12..16], FORMAT_FLAGS);
+    header
+}
+
+/// Returns the checksum stored alongside `payload`, which covers both its encoded length and its
+/// contents.
+fn chunk_checksum(length: &[u8], payload: &[u8]) -> u32 {
+    Crc32::new().update(length).update(payload).finish()
+}
+
+/// Returns `true` if the checksum in `header`, the chunk header read before `payload`, matches
+/// the payload. Chunks of the legacy layout have no checksum, so they always match.
+fn checksum_matches(header: &[u8], version: u32, payload: &[u8]) -> bool {
+    if version == LEGACY_FORMAT_VERSION {
+        return true;
+    }
+
+    let start = marker_length(version);
+    let end = start + length_width(version);
+    LittleEndian::read_u32(&header[end..end + 4]) == chunk_checksum(&header[start..end], payload)
+}
+
+/// Returns the bytes of a complete chunk wrapping `payload`, ready to be written in a single call.
+pub fn encode_chunk(payload: &[u8]) -> Vec<u8> {
+    let mut chunk = vec![0; chunk_header_length(FORMAT_VERSION)];
+    chunk[0..4].copy_from_slice(&CHUNK_MARKER);
+    LittleEndian::write_u64(&mut chunk[4..12], payload.len() as u64);
+    let checksum = chunk_checksum(&chunk[4..12], payload);
+    LittleEndian::write_u32(&mut chunk[12..16], checksum);
+    chunk.extend(payload);
+    chunk
+}
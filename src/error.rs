@@ -16,6 +16,35 @@ pub enum Error {
     TransactionUnpack,
     /// The packed transaction's key is invalid.
     TransactionUnregistered,
+    /// The storage does not begin with a valid header, nor with a complete chunk of the layout
+    /// used before the header was introduced, so it was not written by this library.
+    InvalidHeader,
+    /// The storage was written in a format version that this version of the library does not
+    /// understand.
+    UnsupportedVersion(u32),
+    /// The storage was written with format flags that this version of the library does not
+    /// understand.
+    UnsupportedFlags(u32),
+    /// The storage is corrupt in a way that cannot be explained by an interrupted write, e.g. a
+    /// file that is only ever replaced atomically is invalid.
+    Corrupt,
+    /// A chunk of the given length is larger than the storage's maximum chunk size. When reading,
+    /// this usually means that its length prefix is corrupt.
+    ChunkTooLarge(u64),
+    /// The storage is locked by another storage object, possibly in another process.
+    Locked,
+    /// The storage was opened read-only, so nothing can be stored to it.
+    ReadOnly,
+    /// The object may hold changes that failed to be stored, or that a panicking transaction
+    /// left partway through, so nothing more can be stored until it is reloaded with
+    /// `Protium::reload`.
+    Poisoned,
+    /// A transaction panicked while being applied, with the given message. The object it was
+    /// applied to may be left partway through being changed.
+    Panicked(String),
+    /// The transaction was rejected by `Transaction::check` for the given reason, so it was
+    /// neither applied nor stored.
+    Rejected(Box<StdError + Send + Sync>),
     /// A generic IO error.
     Io(IoError),
 }
@@ -28,6 +57,16 @@ impl StdError for Error {
             Error::TransactionPack => "The transaction failed to be packed for storage",
             Error::TransactionUnpack => "The transaction failed to be unpacked from storage",
             Error::TransactionUnregistered => "The packed transaction's key is invalid",
+            Error::InvalidHeader => "The storage does not begin with a valid header",
+            Error::UnsupportedVersion(_) => "The storage format version is unsupported",
+            Error::UnsupportedFlags(_) => "The storage format flags are unsupported",
+            Error::Corrupt => "The storage is corrupt",
+            Error::ChunkTooLarge(_) => "The chunk exceeds the maximum chunk size",
+            Error::Locked => "The storage is locked by another storage object",
+            Error::ReadOnly => "The storage is read-only",
+            Error::Poisoned => "The object must be reloaded before anything more is stored",
+            Error::Panicked(_) => "A transaction panicked while being applied",
+            Error::Rejected(_) => "The transaction was rejected",
             Error::Io(ref err) => err.description(),
         }
     }
@@ -35,6 +74,7 @@ impl StdError for Error {
     fn cause(&self) -> Option<&StdError> {
         match *self {
             Error::Io(ref err) => err.cause(),
+            Error::Rejected(ref err) => Some(&**err),
             _ => None,
         }
     }
@@ -43,6 +83,17 @@ impl StdError for Error {
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
         match *self {
+            Error::UnsupportedVersion(version) => {
+                write!(f, "{} ({})", self.description(), version)
+            },
+            Error::UnsupportedFlags(flags) => write!(f, "{} ({:#x})", self.description(), flags),
+            Error::ChunkTooLarge(length) => {
+                write!(f, "{} ({} bytes)", self.description(), length)
+            },
+            Error::Panicked(ref message) if !message.is_empty() => {
+                write!(f, "{}: {}", self.description(), message)
+            },
+            Error::Rejected(ref err) => write!(f, "{}: {}", self.description(), err),
             Error::Io(ref err) => Display::fmt(err, f),
             _ => self.description().fmt(f),
         }
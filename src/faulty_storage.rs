@@ -0,0 +1,141 @@
+use super::{Packable, PackedObject, PackedTransaction, Storage, StorageRecovery, Transaction};
+use error::Error;
+
+use std::collections::BTreeMap;
+
+/// The operations of a `Storage` that a `FaultyStorage` can be scripted to fail.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum StorageOperation {
+    /// `Storage::load`.
+    Load,
+    /// `Storage::store_object`.
+    StoreObject,
+    /// `Storage::store_data`.
+    StoreData,
+    /// `Storage::store_batch`.
+    StoreBatch,
+    /// `Storage::flush`.
+    Flush,
+}
+
+/// A storage that wraps another storage (`S`), failing or silently dropping chosen calls to it.
+/// Meant for testing how an application handles storage errors, e.g. `Error::Io` being returned
+/// by `Protium::apply`.
+///
+/// Faults are scripted before the storage is handed to `Protium`, by the number of the call to
+/// fail, counting from 1 for the first call of each operation made on the `FaultyStorage`. Every
+/// call that is not scripted is passed to the wrapped storage.
+pub struct FaultyStorage<S> {
+    storage: S,
+    faults: BTreeMap<(StorageOperation, u64), Option<Error>>,
+    calls: BTreeMap<StorageOperation, u64>,
+}
+
+impl<S> FaultyStorage<S> {
+    /// Initialize a wrapper around `storage` with no faults scripted.
+    pub fn new(storage: S) -> FaultyStorage<S> {
+        FaultyStorage { storage: storage, faults: BTreeMap::new(), calls: BTreeMap::new() }
+    }
+
+    /// Scripts the `call`th call of `operation` to return `Err(error)` without reaching the
+    /// wrapped storage.
+    pub fn fail(mut self, operation: StorageOperation, call: u64, error: Error)
+        -> FaultyStorage<S>
+    {
+        self.faults.insert((operation, call), Some(error));
+        self
+    }
+
+    /// Scripts the `call`th call of `operation` to return `Ok` without reaching the wrapped
+    /// storage, as if whatever it stored were lost. A dropped `load` finds nothing stored.
+    pub fn drop_call(mut self, operation: StorageOperation, call: u64) -> FaultyStorage<S> {
+        self.faults.insert((operation, call), None);
+        self
+    }
+
+    /// Returns the number of calls of `operation` made so far, whether or not they failed.
+    pub fn calls(&self, operation: StorageOperation) -> u64 {
+        self.calls.get(&operation).cloned().unwrap_or(0)
+    }
+
+    /// Returns an immutable reference to the wrapped storage.
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// Returns a mutable reference to the wrapped storage.
+    pub fn storage_mut(&mut self) -> &mut S {
+        &mut self.storage
+    }
+
+    /// Unwraps the wrapped storage.
+    pub fn into_inner(self) -> S {
+        self.storage
+    }
+
+    /// Counts a call of `operation`, returning its scripted fault, if any: `Some(Some(error))` if
+    /// it is to fail, or `Some(None)` if it is to be dropped.
+    fn call(&mut self, operation: StorageOperation) -> Option<Option<Error>> {
+        let call = {
+            let calls = self.calls.entry(operation).or_insert(0);
+            *calls += 1;
+            *calls
+        };
+
+        self.faults.remove(&(operation, call))
+    }
+}
+
+impl<T: Packable, S: Storage<T>> Storage<T> for FaultyStorage<S> {
+    fn load(&mut self) -> Result<Option<(PackedObject, Vec<PackedTransaction>)>, Error> {
+        match self.call(StorageOperation::Load) {
+            Some(Some(err)) => Err(err),
+            Some(None) => Ok(None),
+            None => self.storage.load(),
+        }
+    }
+
+    fn store_object(&mut self, object: &T) -> Result<(), Error> {
+        match self.call(StorageOperation::StoreObject) {
+            Some(Some(err)) => Err(err),
+            Some(None) => Ok(()),
+            None => self.storage.store_object(object),
+        }
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.storage.is_read_only()
+    }
+
+    fn store_data<R: Transaction<T>>(&mut self, object: &T, transaction: &R)
+        -> Result<(), Error>
+    {
+        match self.call(StorageOperation::StoreData) {
+            Some(Some(err)) => Err(err),
+            Some(None) => Ok(()),
+            None => self.storage.store_data(object, transaction),
+        }
+    }
+
+    fn store_batch(&mut self, object: &T, transactions: &[PackedTransaction])
+        -> Result<(), Error>
+    {
+        match self.call(StorageOperation::StoreBatch) {
+            Some(Some(err)) => Err(err),
+            Some(None) => Ok(()),
+            None => self.storage.store_batch(object, transactions),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match self.call(StorageOperation::Flush) {
+            Some(Some(err)) => Err(err),
+            Some(None) => Ok(()),
+            None => self.storage.flush(),
+        }
+    }
+
+    fn recovery(&self) -> StorageRecovery {
+        self.storage.recovery()
+    }
+}
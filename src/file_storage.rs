@@ -1,40 +1,175 @@
-use super::{Packable, PackedObject, PackedTransaction, Storage, Transaction};
+use super::{
+    Packable, PackedObject, PackedTransaction, Storage, StorageRecovery, Transaction, Transactions
+};
+use compaction::{CompactionPolicy, CompactionStats, TransactionLimit};
+use disk::{self, ChunkRead, LockMode};
 use error::Error;
+use salvage::{self, Salvage};
+use sync::{SyncMode, Syncer};
+use verify::{self, ChunkFault, FileScan, VerifyReport};
 
-use byteorder::{self, ByteOrder, LittleEndian, ReadBytesExt};
 use std::fs::{self, File, OpenOptions};
-use std::io::{Read, Write};
+use std::io::{self, Seek, SeekFrom, Write};
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 /// A storage implementation that uses the file system to atomically and durably store a packable
 /// object.
 ///
-/// The storage will be compacted after every 16 transactions, so the storage file does not grow
-/// too large. TODO: Control over compaction.
+/// The storage is compacted according to its `CompactionPolicy`, so the storage file does not grow
+/// too large. By default, it is compacted after every 16 transactions, and each transaction is
+/// synced to the disk before it is considered stored; see `FileStorageOptions` to choose another
+/// `CompactionPolicy` or `SyncMode`.
+///
+/// The file begins with a 16-byte header: the magic bytes `PROTIUM\0`, then the little-endian
+/// `u32` format version, then the little-endian `u32` format flags. The header is followed by a
+/// sequence of chunks: first the packed object, then each packed transaction applied since the
+/// object was stored. A chunk is laid out as a 4-byte marker, a little-endian `u64` payload length,
+/// a little-endian `u32` CRC-32 of the length and payload, then the payload itself. A transaction
+/// payload begins with the little-endian `u32` transaction key. A batch of transactions stored
+/// together is held by a single chunk, so that it is discarded as a unit if it is torn. Chunks
+/// that are truncated or that fail their checksum are treated as the end of the file, and are
+/// truncated away by `load` so that later transactions are not appended after them; see
+/// `FileStorageOptions::quarantine` to keep a copy of them. A file whose object is invalid or
+/// fails to unpack, however, fails to load with `Error::Corrupt` rather than being truncated, and
+/// is left untouched; see `Salvage::recover`.
+///
+/// Files written by earlier revisions of the format, including those written before the header
+/// was introduced, are read as well, and are rewritten in the current format when the storage is
+/// first compacted, i.e. before anything is stored to them. A file without a header is only read
+/// as such if all of it parses, since it has no checksums; otherwise it fails to load with
+/// `Error::InvalidHeader` or `Error::Corrupt` and is left untouched.
 pub struct FileStorage<T: Packable> {
     base_path: PathBuf,
     temp_path: PathBuf,
     file: Option<File>,
     needs_initial_compact: bool,
     transaction_count: u64,
+    log_bytes: u64,
+    snapshot_bytes: u64,
+    compacted_at: Instant,
+    compaction: Box<CompactionPolicy>,
+    discarded_bytes: u64,
+    promoted_temp_file: bool,
+    quarantine: bool,
+    quarantine_path: Option<PathBuf>,
+    read_only: bool,
+    max_chunk_size: u64,
+    syncer: Syncer,
+    // Held only to keep the lock until the storage is dropped.
+    #[allow(dead_code)]
+    lock: Option<File>,
     marker: PhantomData<T>,
 }
 
-impl<T: Packable> FileStorage<T> {
-    /// Creates a new storage object linked to the file at `path`.
+/// Options that configure how a `FileStorage` is opened and maintained.
+pub struct FileStorageOptions {
+    compaction: Box<CompactionPolicy>,
+    lock: LockMode,
+    sync: SyncMode,
+    max_chunk_size: u64,
+    quarantine: bool,
+}
+
+impl FileStorageOptions {
+    /// Initialize with the default options.
+    pub fn new() -> FileStorageOptions {
+        FileStorageOptions {
+            compaction: Box::new(TransactionLimit::default()),
+            lock: LockMode::Exclusive,
+            sync: SyncMode::Data,
+            max_chunk_size: disk::DEFAULT_MAX_CHUNK_SIZE,
+            quarantine: false,
+        }
+    }
+
+    /// Sets the policy that decides when the storage is compacted.
     ///
-    /// If the file at `path` does not exist, it will be created once `read_object` or `read_data`
-    /// is called.
+    /// Defaults to `TransactionLimit(16)`.
+    pub fn compaction<P: CompactionPolicy + 'static>(mut self, policy: P) -> FileStorageOptions {
+        self.compaction = Box::new(policy);
+        self
+    }
+
+    /// Sets the kind of lock held on the storage for as long as it is open.
     ///
-    /// `FileStorage` requires that a special temporary file be writable as well. This special path
-    /// will be `path` with a tilde ("~") appended. The temporary file is used to maintain a
-    /// durable copy of the stored object during the time that the storage is being compacted.
+    /// Defaults to `LockMode::Exclusive`.
+    pub fn lock(mut self, mode: LockMode) -> FileStorageOptions {
+        self.lock = mode;
+        self
+    }
+
+    /// Sets when the data written to the storage is synced to the disk. See `SyncMode` for the
+    /// guarantees that each mode provides.
     ///
-    /// Ensure that `path` is in a directory of which the user has write access.
-    pub fn new<P: AsRef<Path>>(path: P) -> Result<FileStorage<T>, Error> {
+    /// Defaults to `SyncMode::Data`.
+    pub fn sync(mut self, mode: SyncMode) -> FileStorageOptions {
+        self.sync = mode;
+        self
+    }
+
+    /// Sets the largest chunk payload, in bytes, that the storage reads or writes. A packed object
+    /// or transaction larger than this fails to be stored with `Error::ChunkTooLarge`, and so does
+    /// loading a storage holding one, since its length prefix is most likely corrupt. This bounds
+    /// the memory allocated for a chunk before its checksum can be verified.
+    ///
+    /// Defaults to 1 GiB, so it must be raised to store larger objects.
+    pub fn max_chunk_size(mut self, bytes: u64) -> FileStorageOptions {
+        self.max_chunk_size = bytes;
+        self
+    }
+
+    /// Sets whether torn or corrupt data is kept for later analysis rather than discarded. If set,
+    /// everything after the last valid chunk is copied to a sidecar file before `load` truncates
+    /// it. The sidecar is named after the file with the time, in milliseconds since the Unix
+    /// epoch, and `.corrupt` appended, e.g. `storage.1700000000000.corrupt`, and is never
+    /// removed by the storage.
+    ///
+    /// Defaults to `false`.
+    pub fn quarantine(mut self, quarantine: bool) -> FileStorageOptions {
+        self.quarantine = quarantine;
+        self
+    }
+
+    /// Walks every chunk of the file at `path` and describes what it holds, as
+    /// `FileStorage::verify` does, but treats chunks as too large exactly as a storage opened with
+    /// these options would.
+    pub fn verify<T: Packable, P: AsRef<Path>>(&self, path: P) -> Result<VerifyReport, Error> {
+        verify_path::<T>(path.as_ref(), self.max_chunk_size, None)
+    }
+
+    /// Verifies the file at `path` as `FileStorage::verify_replay` does, but treats chunks as too
+    /// large exactly as a storage opened with these options would.
+    pub fn verify_replay<T: Packable, P: AsRef<Path>>(&self, path: P,
+                                                       transactions: &Transactions<T>)
+        -> Result<VerifyReport, Error>
+    {
+        verify_path(path.as_ref(), self.max_chunk_size, Some(transactions))
+    }
+
+    /// Reads every valid record of the file at `path`, as `FileScan::read` does, but treats chunks
+    /// as too large exactly as a storage opened with these options would.
+    pub fn scan<P: AsRef<Path>>(&self, path: P) -> Result<FileScan, Error> {
+        verify::scan_file(path.as_ref(), self.max_chunk_size)
+    }
+
+    /// Recovers what can be read from the damaged file at `path`, as `Salvage::recover` does, but
+    /// treats chunks as too large exactly as a storage opened with these options would.
+    pub fn salvage<P: AsRef<Path>>(&self, path: P) -> Result<Salvage, Error> {
+        salvage::recover_file(path.as_ref(), self.max_chunk_size)
+    }
+
+    /// Creates a new storage object linked to the file at `path` using these options.
+    ///
+    /// See `FileStorage::new` for details.
+    ///
+    /// Returns `Err(Error::Locked)` if another storage holds a conflicting lock on `path`.
+    pub fn open<T: Packable, P: AsRef<Path>>(self, path: P) -> Result<FileStorage<T>, Error> {
         let base_path = PathBuf::from(path.as_ref());
         let temp_path = PathBuf::from(format!("{}~", path.as_ref().display()));
+        let lock_path = PathBuf::from(format!("{}.lock", path.as_ref().display()));
+        let lock = try!(disk::acquire_lock(&lock_path, self.lock));
 
         let mut result = FileStorage {
             base_path: base_path,
@@ -42,22 +177,109 @@ impl<T: Packable> FileStorage<T> {
             file: None,
             needs_initial_compact: true,
             transaction_count: 0,
+            log_bytes: 0,
+            snapshot_bytes: 0,
+            compacted_at: Instant::now(),
+            compaction: self.compaction,
+            discarded_bytes: 0,
+            promoted_temp_file: false,
+            quarantine: self.quarantine,
+            quarantine_path: None,
+            read_only: self.lock != LockMode::Exclusive,
+            max_chunk_size: self.max_chunk_size,
+            syncer: Syncer::new(self.sync),
+            lock: lock,
             marker: PhantomData,
         };
 
+        if result.read_only {
+            return result.open_base();
+        }
+
         // Hackish. `metadata` returns `Err` if the path does not exist. Change once `PathExt`
         // stabilizes.
-        if fs::metadata(&result.base_path).is_err() {
-            if fs::metadata(&result.temp_path).is_err() {
-                return Ok(result);
-            } else {
+        let base_exists = fs::metadata(&result.base_path).is_ok();
+
+        // A leftover temporary file means that compaction was interrupted before the rename. If
+        // the base file exists, it is still authoritative and the temporary file is discarded.
+        // Otherwise, the temporary file holds the very first stored object, which is promoted only
+        // if it was written completely.
+        if fs::metadata(&result.temp_path).is_ok() {
+            let promote = !base_exists &&
+                try!(disk::is_complete_object(&result.temp_path, result.max_chunk_size));
+
+            if promote {
                 try!(fs::rename(&result.temp_path, &result.base_path));
+                result.promoted_temp_file = true;
+            } else {
+                try!(fs::remove_file(&result.temp_path));
             }
+
+            try!(result.syncer.sync_directory(&result.base_path));
         }
 
-        let file = OpenOptions::new().read(true).write(true).append(true).open(&result.base_path);
-        result.file = Some(try!(file));
-        Ok(result)
+        result.open_base()
+    }
+}
+
+impl Default for FileStorageOptions {
+    fn default() -> FileStorageOptions {
+        FileStorageOptions::new()
+    }
+}
+
+impl<T: Packable> FileStorage<T> {
+    /// Creates a new storage object linked to the file at `path`.
+    ///
+    /// If the file at `path` does not exist, it will be created once `read_object` or `read_data`
+    /// is called.
+    ///
+    /// `FileStorage` requires that a special temporary file be writable as well. This special path
+    /// will be `path` with a tilde ("~") appended. The temporary file is used to maintain a
+    /// durable copy of the stored object during the time that the storage is being compacted. A
+    /// temporary file left behind by an interrupted compaction is discarded when the storage is
+    /// opened, unless there is no file at `path` and the temporary file holds a complete object,
+    /// in which case it is renamed to `path`.
+    ///
+    /// Ensure that `path` is in a directory of which the user has write access.
+    ///
+    /// The storage is opened with the default `FileStorageOptions`.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<FileStorage<T>, Error> {
+        FileStorageOptions::new().open(path)
+    }
+
+    /// Opens the file at `path` for inspection, without taking any lock.
+    ///
+    /// The storage never writes to, truncates or renames any file, so it is safe to use while
+    /// another process has the same path open for writing. Each call to `load` reads a consistent
+    /// prefix of whatever the writer has durably stored at that time. Storing to the storage
+    /// fails with `Error::ReadOnly`, as does `Protium::apply` on a `Protium` built from it.
+    ///
+    /// This is equivalent to opening the storage with `LockMode::Unlocked`.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<FileStorage<T>, Error> {
+        FileStorageOptions::new().lock(LockMode::Unlocked).open(path)
+    }
+
+    /// Walks every chunk of the file at `path` and describes what it holds, without building the
+    /// object. Like `open_read_only`, this takes no lock and never modifies any file, so it is
+    /// safe to use while another process has the same path open for writing.
+    ///
+    /// Chunks larger than the default maximum chunk size are reported as invalid; see
+    /// `FileStorageOptions::verify` to verify a file written with another maximum.
+    ///
+    /// Returns `Err` if the file's header is invalid or an IO error occurs. Torn or corrupt data
+    /// is described by the report instead.
+    pub fn verify<P: AsRef<Path>>(path: P) -> Result<VerifyReport, Error> {
+        FileStorageOptions::new().verify::<T, _>(path)
+    }
+
+    /// Verifies the file at `path` as `verify` does, then also unpacks the object and replays
+    /// every valid transaction on it using `transactions`, recording the outcome in
+    /// `VerifyReport::replay`.
+    pub fn verify_replay<P: AsRef<Path>>(path: P, transactions: &Transactions<T>)
+        -> Result<VerifyReport, Error>
+    {
+        FileStorageOptions::new().verify_replay(path, transactions)
     }
 
     /// Returns a reference of the path used to serve this storage.
@@ -65,109 +287,298 @@ impl<T: Packable> FileStorage<T> {
         &self.base_path
     }
 
-    fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, Error> {
-        let mut file = match self.file {
+    /// Returns the current state of the transaction log, as seen by the compaction policy.
+    pub fn compaction_stats(&self) -> CompactionStats {
+        CompactionStats {
+            transaction_count: self.transaction_count,
+            log_bytes: self.log_bytes,
+            snapshot_bytes: self.snapshot_bytes,
+            elapsed: self.compacted_at.elapsed(),
+        }
+    }
+
+    /// Returns the number of bytes of torn or corrupt data that were truncated from the end of the
+    /// file by the last call to `load`.
+    pub fn discarded_bytes(&self) -> u64 {
+        self.discarded_bytes
+    }
+
+    /// Returns the path of the sidecar file to which the last call to `load` copied torn or corrupt
+    /// data, if any. See `FileStorageOptions::quarantine`.
+    pub fn quarantine_path(&self) -> Option<&Path> {
+        self.quarantine_path.as_deref()
+    }
+
+    /// Opens the base file, if it exists, and validates its header.
+    fn open_base(mut self) -> Result<FileStorage<T>, Error> {
+        // Hackish. `metadata` returns `Err` if the path does not exist. Change once `PathExt`
+        // stabilizes.
+        if fs::metadata(&self.base_path).is_err() {
+            return Ok(self);
+        }
+
+        let file = try!(OpenOptions::new().read(true).append(!self.read_only)
+            .open(&self.base_path));
+
+        if !self.read_only {
+            try!(self.syncer.track(&file));
+        }
+
+        self.file = Some(file);
+        try!(self.read_header());
+        Ok(self)
+    }
+
+    /// Reads and validates the file header, returning the format version of the file, which is
+    /// `LEGACY_FORMAT_VERSION` for a file written before the header was introduced.
+    ///
+    /// Returns `Ok(None)` if there is no file or if the file ends partway through a valid header.
+    fn read_header(&mut self) -> Result<Option<u32>, Error> {
+        match self.file {
+            Some(ref file) => disk::read_storage_header(file, self.max_chunk_size),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the length of the file, or `0` if there is none.
+    fn file_length(&self) -> Result<u64, Error> {
+        match self.file {
+            Some(ref file) => Ok(try!(file.metadata()).len()),
+            None => Ok(0),
+        }
+    }
+
+    fn read_chunk(&mut self, version: u32) -> Result<Option<Vec<u8>>, Error> {
+        match self.file {
+            Some(ref file) => disk::read_chunk(file, version, self.max_chunk_size),
+            None => Ok(None),
+        }
+    }
+
+    /// Truncates the file to `length` bytes, discarding everything after the last valid chunk, or
+    /// first copying it to a sidecar file if the storage quarantines corrupt data.
+    ///
+    /// A read-only storage only records how many bytes it ignored.
+    fn truncate_tail(&mut self, length: u64) -> Result<(), Error> {
+        let file = match self.file {
             Some(ref file) => file,
-            None => return Ok(None),
+            None => return Ok(()),
         };
 
-        let length = match file.read_u32::<LittleEndian>() {
-            Ok(length) => length,
-            Err(byteorder::Error::UnexpectedEOF) => return Ok(None),
-            Err(byteorder::Error::Io(err)) => return Err(err.into()),
-        } as usize;
+        let file_length = try!(file.metadata()).len();
+        self.discarded_bytes = file_length.saturating_sub(length);
 
-        let mut buf = Vec::with_capacity(length);
-        let length_read = try!(file.take(length as u64).read_to_end(&mut buf));
+        if self.discarded_bytes > 0 && !self.read_only {
+            if self.quarantine {
+                self.quarantine_path =
+                    Some(try!(quarantine(file, length, &self.base_path, &self.syncer)));
+            }
 
-        if length == length_read {
-            Ok(Some(buf))
-        } else {
-            Ok(None)
+            try!(file.set_len(length));
+            try!(self.syncer.sync_file(file));
         }
+
+        Ok(())
     }
 
-    fn read_object(&mut self) -> Result<Option<PackedObject>, Error> {
-        Ok(try!(self.read_chunk()).map(|data| PackedObject(data)))
+    /// Reads the object, which must be the first chunk of any file holding more than a header.
+    ///
+    /// Returns `Err(Error::Corrupt)` if the object is invalid, since it is only ever written to
+    /// the temporary file, which is renamed over the base file once it is complete.
+    fn read_object(&mut self, version: u32) -> Result<Option<PackedObject>, Error> {
+        let read = match self.file {
+            Some(ref file) => try!(disk::scan_chunk(file, version, self.max_chunk_size)),
+            None => return Ok(None),
+        };
+
+        match read {
+            ChunkRead::Chunk(data) => Ok(Some(PackedObject(data))),
+            ChunkRead::End => Ok(None),
+            ChunkRead::Fault(ChunkFault::TooLarge(length)) => Err(Error::ChunkTooLarge(length)),
+            ChunkRead::Fault(_) => Err(Error::Corrupt),
+        }
     }
 
-    fn read_transaction(&mut self) -> Result<Option<PackedTransaction>, Error> {
-        let data = match try!(self.read_chunk()) {
-            Some(data) => data,
+    /// Reads the transactions held by the next chunk, along with the length of the chunk.
+    fn read_transactions(&mut self, version: u32)
+        -> Result<Option<(Vec<PackedTransaction>, u64)>, Error>
+    {
+        let payload = match try!(self.read_chunk(version)) {
+            Some(payload) => payload,
             None => return Ok(None),
         };
 
-        if data.len() < 4 {
-            return Ok(None);
+        let length = (disk::chunk_header_length(version) + payload.len()) as u64;
+        Ok(disk::decode_transactions(payload, version).map(|transactions| (transactions, length)))
+    }
+
+    /// Returns `true` if the object must be stored in place of the next transactions.
+    fn needs_compact(&self) -> bool {
+        self.file.is_none() || self.needs_initial_compact ||
+            self.compaction.should_compact(&self.compaction_stats())
+    }
+
+    /// Appends a chunk holding `count` transactions to the file, syncing it as the `SyncMode`
+    /// requires.
+    fn append(&mut self, chunk: &[u8], count: u64) -> Result<(), Error> {
+        {
+            let mut file = self.file.as_ref().unwrap();
+            try!(file.write_all(chunk));
+            try!(file.flush());
         }
 
-        let mut code = data;
-        let data = code.split_off(4);
-        let code = LittleEndian::read_u32(&code);
-        Ok(Some(PackedTransaction(code, data)))
+        try!(self.syncer.written());
+
+        self.transaction_count += count;
+        self.log_bytes += chunk.len() as u64;
+        Ok(())
     }
 }
 
 impl<T: Packable> Storage<T> for FileStorage<T> {
     fn load(&mut self) -> Result<Option<(PackedObject, Vec<PackedTransaction>)>, Error> {
-        let object = match try!(self.read_object()) {
+        self.discarded_bytes = 0;
+        self.quarantine_path = None;
+
+        // A writer in another process may have since created the file or replaced it during
+        // compaction, so a read-only storage always reads from the latest file.
+        if self.read_only {
+            self.file = None;
+
+            if fs::metadata(&self.base_path).is_ok() {
+                self.file = Some(try!(File::open(&self.base_path)));
+            }
+        } else if self.file.is_none() && fs::metadata(&self.base_path).is_ok() {
+            // A compaction failed after letting go of the previous file.
+            let file = try!(OpenOptions::new().read(true).append(true).open(&self.base_path));
+            try!(self.syncer.track(&file));
+            self.file = Some(file);
+        }
+
+        // A file that holds anything but fails to parse is never reported as empty, since the
+        // default object would then be stored over it.
+        let version = match try!(self.read_header()) {
+            Some(version) => version,
+            None if try!(self.file_length()) == 0 => return Ok(None),
+            None => return Err(Error::Corrupt),
+        };
+
+        let mut valid_length = disk::header_length(version);
+
+        let object = match try!(self.read_object(version)) {
             Some(object) => object,
             None => return Ok(None),
         };
 
+        valid_length += (disk::chunk_header_length(version) + object.0.len()) as u64;
+
         let mut transactions = vec![];
         self.transaction_count = 0;
+        self.log_bytes = 0;
+        self.snapshot_bytes = object.0.len() as u64;
+        self.compacted_at = Instant::now();
 
-        loop {
-            match try!(self.read_transaction()) {
-                Some(transaction) => {
-                    transactions.push(transaction);
-                    self.transaction_count += 1;
-                },
-                None => {
-                    return Ok(Some((object, transactions)));
-                },
-            }
+        while let Some((chunk_transactions, length)) = try!(self.read_transactions(version)) {
+            self.transaction_count += chunk_transactions.len() as u64;
+            self.log_bytes += length;
+            transactions.extend(chunk_transactions);
+        }
+
+        valid_length += self.log_bytes;
+
+        // What looks like a torn tail may be the rest of a misread file, so only a file whose
+        // object unpacks is truncated. A legacy file has no checksums to tell the two apart.
+        if valid_length < try!(self.file_length()) &&
+            (version == disk::LEGACY_FORMAT_VERSION || T::unpack(&object.0).is_err())
+        {
+            return Err(Error::Corrupt);
         }
+
+        try!(self.truncate_tail(valid_length));
+
+        // Now that the file ends with a valid chunk, transactions may be appended to it directly,
+        // unless it was written by an earlier revision of the format.
+        self.needs_initial_compact = version != disk::FORMAT_VERSION;
+        Ok(Some((object, transactions)))
     }
 
     fn store_object(&mut self, object: &T) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+
         let packed = match object.pack() {
             Ok(packed) => packed,
             Err(()) => return Err(Error::ObjectPack),
         };
+        try!(disk::check_chunk_length(packed.len() as u64, self.max_chunk_size));
 
+        // The object is first written to the temporary file, which is truncated in case a larger
+        // one was left behind. A crash at any point before the rename leaves the base file intact,
+        // and the temporary file is discarded when the storage is next opened.
         {
-            let mut temp = try!(OpenOptions::new().write(true).create(true).open(&self.temp_path));
-            let mut buf = [0; 4];
-            LittleEndian::write_u32(&mut buf, packed.len() as u32);
-            try!(temp.write_all(&buf));
-            try!(temp.write_all(&packed));
+            let mut temp = try!(OpenOptions::new().write(true).create(true).truncate(true)
+                .open(&self.temp_path));
+            try!(temp.write_all(&disk::encode_header()));
+            try!(temp.write_all(&disk::encode_chunk(&packed)));
             try!(temp.flush());
-            try!(temp.sync_data());
+            try!(self.syncer.sync_file(&temp));
         }
 
         self.file.take();
+        self.syncer.discard();
 
-        // Hackish, again.
-        if fs::metadata(&self.base_path).is_ok() {
-            try!(fs::remove_file(&self.base_path));
-        }
-
+        // Renaming over the base file atomically replaces it, so either the old or the new file is
+        // found under the base path. The rename is durable only once the directory is synced.
         try!(fs::rename(&self.temp_path, &self.base_path));
+        try!(self.syncer.sync_directory(&self.base_path));
 
         self.transaction_count = 0;
+        self.log_bytes = 0;
+        self.snapshot_bytes = packed.len() as u64;
+        self.compacted_at = Instant::now();
         self.needs_initial_compact = false;
-        let file = OpenOptions::new().read(true).write(true).append(true).open(&self.base_path);
-        self.file = Some(try!(file));
+        let file = try!(OpenOptions::new().read(true).write(true).append(true)
+            .open(&self.base_path));
+        try!(self.syncer.track(&file));
+        self.file = Some(file);
 
         Ok(())
     }
 
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if self.read_only {
+            return Ok(());
+        }
+
+        // Nothing has been synced, not even the last compaction, so the whole file is synced.
+        if self.syncer.mode() == SyncMode::None && self.file.is_some() {
+            try!(disk::sync_file(&self.base_path));
+            try!(disk::sync_directory(&self.base_path));
+        }
+
+        self.syncer.flush()
+    }
+
+    fn recovery(&self) -> StorageRecovery {
+        StorageRecovery {
+            promoted_temp_file: self.promoted_temp_file,
+            discarded_bytes: self.discarded_bytes,
+        }
+    }
+
     fn store_data<R: Transaction<T>>(&mut self, object: &T, transaction: &R)
         -> Result<(), Error>
     {
-        if self.file.is_none() || self.needs_initial_compact || self.transaction_count >= 16 {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        if self.needs_compact() {
             return self.store_object(object);
         }
 
@@ -176,16 +587,53 @@ impl<T: Packable> Storage<T> for FileStorage<T> {
             Err(()) => return Err(Error::TransactionPack),
         };
 
-        let mut file = self.file.as_mut().unwrap();
-        let mut buf = [0; 4];
-        LittleEndian::write_u32(&mut buf, (packed.len() + 4) as u32);
-        try!(file.write_all(&buf));
-        LittleEndian::write_u32(&mut buf, R::key());
-        try!(file.write_all(&buf));
-        try!(file.write_all(&packed));
-        try!(file.flush());
-        try!(file.sync_data());
-        self.transaction_count += 1;
-        Ok(())
+        let payload = disk::encode_transaction(R::key(), &packed);
+        try!(disk::check_chunk_length(payload.len() as u64, self.max_chunk_size));
+        self.append(&disk::encode_chunk(&payload), 1)
     }
+
+    fn store_batch(&mut self, object: &T, transactions: &[PackedTransaction])
+        -> Result<(), Error>
+    {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        if transactions.is_empty() {
+            return Ok(());
+        }
+
+        if self.needs_compact() {
+            return self.store_object(object);
+        }
+
+        let payload = disk::encode_batch(transactions);
+        try!(disk::check_chunk_length(payload.len() as u64, self.max_chunk_size));
+        self.append(&disk::encode_chunk(&payload), transactions.len() as u64)
+    }
+}
+
+fn verify_path<T: Packable>(path: &Path, max_length: u64, transactions: Option<&Transactions<T>>)
+    -> Result<VerifyReport, Error>
+{
+    let temp_path = PathBuf::from(format!("{}~", path.display()));
+    verify::verify_file(path, &temp_path, max_length, transactions)
+}
+
+/// Copies everything in `file` from `offset` onwards to a new sidecar file next to `path`, named
+/// after the current time, and returns the path of the sidecar.
+fn quarantine(mut file: &File, offset: u64, path: &Path, syncer: &Syncer)
+    -> Result<PathBuf, Error>
+{
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    let millis = now.as_secs() * 1000 + now.subsec_millis() as u64;
+    let sidecar_path = PathBuf::from(format!("{}.{}.corrupt", path.display(), millis));
+
+    let mut sidecar = try!(OpenOptions::new().write(true).create_new(true).open(&sidecar_path));
+    try!(file.seek(SeekFrom::Start(offset)));
+    try!(io::copy(&mut file, &mut sidecar));
+    try!(sidecar.flush());
+    try!(syncer.sync_file(&sidecar));
+    try!(syncer.sync_directory(&sidecar_path));
+    Ok(sidecar_path)
 }
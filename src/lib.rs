@@ -1,19 +1,44 @@
 extern crate byteorder;
 
+pub mod compaction;
+pub mod crash;
+mod crc32;
+mod disk;
 mod error;
+mod faulty_storage;
 mod file_storage;
+mod memory_storage;
+mod salvage;
+mod segmented_storage;
+mod sync;
+mod verify;
 
+pub use compaction::CompactionPolicy;
 pub use error::Error;
-pub use file_storage::FileStorage;
+pub use disk::LockMode;
+pub use faulty_storage::{FaultyStorage, StorageOperation};
+pub use file_storage::{FileStorage, FileStorageOptions};
+pub use memory_storage::MemoryStorage;
+pub use salvage::{Salvage, SkippedRange};
+pub use segmented_storage::{SegmentedStorage, SegmentedStorageOptions};
+pub use sync::SyncMode;
+pub use verify::{BadChunk, ChunkFault, FileScan, Record, VerifyReport};
 
 use std::collections::BTreeMap;
 use std::default::Default;
+use std::error::Error as StdError;
 use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
 
 /// A type that represents a unique key for each corresponding `Transaction` of a `Packable`
 /// object.
+///
+/// The key `BATCH_TRANSACTION_KEY` is reserved and cannot be used by any transaction type.
 pub type TransactionKey = u32;
 
+/// The transaction key reserved by storage implementations to mark a batch of transactions.
+pub const BATCH_TRANSACTION_KEY: TransactionKey = 0xffffffff;
+
 /// A trait that allows its implementer to be packed into and unpacked from a chunk of bytes.
 pub trait Packable: Sized {
     /// Converts the object to an encoded chunk of bytes that can later be unpacked.
@@ -29,54 +54,191 @@ pub trait Packable: Sized {
 
 /// A trait that represents a single atomic change to be made to a `Packable` object (`T`).
 pub trait Transaction<T: Packable>: Packable {
+    /// The value returned by `apply`, e.g. an ID assigned to a new value, or `()` if there is
+    /// nothing to return.
+    ///
+    /// Every implementation must declare this, even as `type Output = ();`, since defaults for
+    /// associated types are not yet stable.
+    type Output;
+
     /// The unique key that represents this transaction that is used by `Storage` implementations
     /// to pack and unpack transactions of this type.
     fn key() -> TransactionKey;
 
-    /// Modifies a given `T` in some way.
-    fn apply(&self, &mut T);
+    /// Returns `Err` with the reason if the transaction must not be applied to a given `T`, e.g.
+    /// because it would break an invariant of `T`. This is called by `Protium::apply` and
+    /// `Batch::commit` before the transaction is applied or stored, but not when transactions
+    /// that were already stored are replayed.
+    ///
+    /// The default implementation accepts every transaction.
+    fn check(&self, _: &T) -> Result<(), Box<StdError + Send + Sync>> {
+        Ok(())
+    }
+
+    /// Modifies a given `T` in some way, returning anything the caller needs to know about the
+    /// change. The output is returned by `Protium::apply`, but discarded when the transaction is
+    /// applied by `Batch::commit` or replayed.
+    fn apply(&self, &mut T) -> Self::Output;
 }
 
+/// A callback registered by `Protium::observe`.
+type Observer<T> = Box<FnMut(&PackedTransaction, &T)>;
+
 /// The prominent structure that exposes a packable object linked to durable storange.
 pub struct Protium<T: Packable + Default, S: Storage<T>> {
     object: T,
     storage: S,
     transactions: Transactions<T>,
+    observers: Vec<Observer<T>>,
+    poisoned: bool,
 }
 
 impl<T: Packable + Default, S: Storage<T>> Protium<T, S> {
     /// Initialize a durably stored object backed by `storage`.
     ///
     /// All possible `Transaction` types to be supported by this object are passed in as
-    /// `transactions`. If the storage is uninitialized, `T::default()` is stored and used.
+    /// `transactions`. If the storage is uninitialized, `T::default()` is stored and used. A
+    /// read-only storage is left uninitialized, and `T::default()` is only used.
     ///
     /// Returns `Err` if an IO error occurred during initializing the object from `storage`.
-    pub fn new(mut storage: S, transactions: Transactions<T>) -> Result<Protium<T, S>, Error> {
-        let object = match try!(storage.load()) {
-            Some((object, tx)) => try!(transactions.unpack(object, tx)),
-            None => {
-                let result = T::default();
-                try!(storage.store_object(&result));
-                result
-            },
-        };
+    pub fn new(storage: S, transactions: Transactions<T>) -> Result<Protium<T, S>, Error> {
+        Protium::new_with_report(storage, transactions).map(|(protium, _)| protium)
+    }
 
-        Ok(Protium { object: object, storage: storage, transactions: transactions })
+    /// Initialize a durably stored object backed by `storage`, as `new` does, also returning a
+    /// report of what was recovered from the storage.
+    ///
+    /// Returns `Err` if an IO error occurred during initializing the object from `storage`.
+    pub fn new_with_report(mut storage: S, transactions: Transactions<T>)
+        -> Result<(Protium<T, S>, RecoveryReport), Error>
+    {
+        let (object, report) = try!(load_object(&mut storage, &transactions));
+
+        let protium = Protium {
+            object: object,
+            storage: storage,
+            transactions: transactions,
+            observers: vec![],
+            poisoned: false,
+        };
+        Ok((protium, report))
     }
 
-    /// Apply `transaction` to the internal object, storing the data durably.
+    /// Apply `transaction` to the internal object, storing the data durably, and return the output
+    /// of `Transaction::apply`.
+    ///
+    /// Returns `Err(Error::Rejected)`, leaving the object and the storage unchanged, if
+    /// `Transaction::check` rejects the transaction.
+    ///
+    /// Returns `Err(Error::ReadOnly)`, leaving the object unchanged, if the storage is read-only.
+    ///
+    /// Returns `Err` if the storage fails to store the transaction. The object has already been
+    /// changed by then, so it is poisoned: see `reload`.
+    ///
+    /// Returns `Err(Error::Panicked)` if `Transaction::apply` panics, poisoning the object, since
+    /// the panic may have left it partway through being changed. Nothing is stored.
+    ///
+    /// Returns `Err(Error::Poisoned)`, leaving the object unchanged, if the object is poisoned.
     ///
     /// # Panics
     ///
     /// Panics if `R` is not a registered transaction type.
-    pub fn apply<R: Transaction<T>>(&mut self, transaction: R) -> Result<(), Error> {
+    pub fn apply<R: Transaction<T>>(&mut self, transaction: R) -> Result<R::Output, Error> {
         if !self.transactions.is_transaction_registered::<R>() {
             panic!("Unregistered transaction type {}", R::key());
         }
 
-        transaction.apply(&mut self.object);
-        try!(self.storage.store_data(&self.object, &transaction));
-        Ok(())
+        if self.storage.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+
+        if self.poisoned {
+            return Err(Error::Poisoned);
+        }
+
+        try!(transaction.check(&self.object).map_err(Error::Rejected));
+
+        // Observers are given the packed transaction, so it is packed before anything changes.
+        let packed = if self.observers.is_empty() {
+            None
+        } else {
+            let data = try!(transaction.pack().map_err(|_| Error::TransactionPack));
+            Some(PackedTransaction(R::key(), data))
+        };
+
+        let output = match catch_panic(|| transaction.apply(&mut self.object)) {
+            Ok(output) => output,
+            Err(err) => {
+                self.poisoned = true;
+                return Err(err);
+            },
+        };
+
+        if let Err(err) = self.storage.store_data(&self.object, &transaction) {
+            self.poisoned = true;
+            return Err(err);
+        }
+
+        if let Some(packed) = packed {
+            self.notify(&[packed]);
+        }
+
+        Ok(output)
+    }
+
+    /// Registers `observer` to be called after each transaction applied by `apply` or by
+    /// `Batch::commit` has been stored, with the packed transaction and the updated object.
+    /// Observers are called in the order in which they were registered. They are not called for
+    /// the transactions replayed when the object is loaded, nor for transactions that failed to be
+    /// stored.
+    ///
+    /// The transactions of a batch are passed in turn once the whole batch has been stored, each
+    /// with the object as updated by the whole batch.
+    ///
+    /// To receive changes on another thread, send them from the observer over a channel.
+    pub fn observe<F: FnMut(&PackedTransaction, &T) + 'static>(&mut self, observer: F) {
+        self.observers.push(Box::new(observer));
+    }
+
+    /// Replaces the internal object with the one loaded from the storage, returning a report of
+    /// what was recovered from the storage as `new_with_report` does.
+    ///
+    /// This is how an object that was poisoned by a failure to store a transaction is made
+    /// consistent with its storage again: the object then holds exactly the transactions that
+    /// would be recovered after a restart.
+    ///
+    /// Returns `Err` if an IO error occurred while loading the object, in which case the object
+    /// remains poisoned, or becomes so.
+    pub fn reload(&mut self) -> Result<RecoveryReport, Error> {
+        self.poisoned = true;
+        let (object, report) = try!(load_object(&mut self.storage, &self.transactions));
+        self.object = object;
+        self.poisoned = false;
+        Ok(report)
+    }
+
+    /// Returns `true` if the internal object may hold changes that failed to be stored, or may
+    /// have been left partway through being changed by a panic, so nothing more can be applied
+    /// until it is reloaded with `reload`.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    /// Makes every transaction applied so far durable, even if the storage's `SyncMode` defers or
+    /// skips syncing.
+    ///
+    /// Returns `Err` if an IO error occurred while syncing the storage.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.storage.flush()
+    }
+
+    /// Begins a batch of transactions that are applied together and stored durably as a unit,
+    /// with a single write to the storage.
+    ///
+    /// The transactions are not applied to the internal object until `Batch::commit` is called.
+    /// Dropping the batch without committing it discards its transactions.
+    pub fn batch(&mut self) -> Batch<'_, T, S> {
+        Batch { protium: self, transactions: vec![], applies: vec![] }
     }
 
     /// Returns an immutable reference to the internal object.
@@ -93,6 +255,142 @@ impl<T: Packable + Default, S: Storage<T>> Protium<T, S> {
     pub fn transactions(&self) -> &Transactions<T> {
         &self.transactions
     }
+
+    /// Calls every observer with each of `transactions`, which have been stored.
+    fn notify(&mut self, transactions: &[PackedTransaction]) {
+        for transaction in transactions {
+            for observer in &mut self.observers {
+                observer(transaction, &self.object);
+            }
+        }
+    }
+}
+
+/// What happened while a `Protium` object was initialized from its storage, as returned by
+/// `Protium::new_with_report`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RecoveryReport {
+    /// `true` if the storage held no object, so `T::default()` was used.
+    pub created_default: bool,
+    /// `true` if the storage held no object of its own, and one was promoted from a temporary file
+    /// left behind by an interrupted write.
+    pub promoted_temp_file: bool,
+    /// The number of stored transactions replayed on the stored object.
+    pub replayed_transactions: u64,
+    /// The number of bytes of torn or corrupt data that the storage discarded.
+    pub discarded_bytes: u64,
+}
+
+impl RecoveryReport {
+    /// Returns `true` if the storage was last closed cleanly, i.e. nothing had to be promoted or
+    /// discarded.
+    pub fn is_clean(&self) -> bool {
+        !self.promoted_temp_file && self.discarded_bytes == 0
+    }
+}
+
+/// A group of transactions to be applied to a `Protium` object together. See `Protium::batch`.
+pub struct Batch<'a, T: Packable + Default + 'a, S: Storage<T> + 'a> {
+    protium: &'a mut Protium<T, S>,
+    transactions: Vec<PackedTransaction>,
+    applies: Vec<Box<Fn(&mut T) -> Result<(), Error> + 'a>>,
+}
+
+impl<'a, T: Packable + Default, S: Storage<T>> Batch<'a, T, S> {
+    /// Adds `transaction` to the end of the batch.
+    ///
+    /// Returns `Err(Error::TransactionPack)`, leaving the batch unchanged, if the transaction
+    /// could not be packed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `R` is not a registered transaction type.
+    pub fn apply<R: Transaction<T> + 'a>(&mut self, transaction: R) -> Result<(), Error> {
+        if !self.protium.transactions.is_transaction_registered::<R>() {
+            panic!("Unregistered transaction type {}", R::key());
+        }
+
+        let packed = try!(transaction.pack().map_err(|_| Error::TransactionPack));
+        self.transactions.push(PackedTransaction(R::key(), packed));
+        self.applies.push(Box::new(move |object: &mut T| {
+            try!(transaction.check(object).map_err(Error::Rejected));
+            transaction.apply(object);
+            Ok(())
+        }));
+        Ok(())
+    }
+
+    /// Returns the number of transactions in the batch.
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    /// Returns `true` if the batch holds no transactions.
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    /// Applies every transaction in the batch to the internal object, in order, then stores them
+    /// durably as a unit. If the system fails while they are being stored, either all or none of
+    /// them are recovered.
+    ///
+    /// Each transaction is checked by `Transaction::check` against the object as updated by the
+    /// transactions before it in the batch. Returns `Err(Error::Rejected)`, leaving the object and
+    /// the storage unchanged, if any transaction is rejected. If the transactions before it have
+    /// already been applied, the object is restored by reloading it from the storage, as
+    /// `Protium::reload` does; if that fails, the object is left poisoned.
+    ///
+    /// Returns `Err(Error::ReadOnly)`, leaving the object unchanged, if the storage is read-only.
+    ///
+    /// Returns `Err` if the storage fails to store the batch, or `Err(Error::Panicked)` if any
+    /// transaction panics while being applied, poisoning the object as `Protium::apply` does.
+    /// Returns `Err(Error::Poisoned)` if the object is already poisoned.
+    pub fn commit(self) -> Result<(), Error> {
+        if self.transactions.is_empty() {
+            return Ok(());
+        }
+
+        if self.protium.storage.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+
+        if self.protium.poisoned {
+            return Err(Error::Poisoned);
+        }
+
+        for (index, apply) in self.applies.iter().enumerate() {
+            let applied = {
+                let object = &mut self.protium.object;
+                catch_panic(|| apply(object))
+            };
+
+            match applied {
+                Ok(Ok(())) => (),
+                Ok(Err(err)) => {
+                    // Nothing has been stored yet, so the storage still holds the object as it
+                    // was before the batch.
+                    if index > 0 {
+                        let _ = self.protium.reload();
+                    }
+
+                    return Err(err);
+                },
+                Err(err) => {
+                    self.protium.poisoned = true;
+                    return Err(err);
+                },
+            }
+        }
+
+        let stored = self.protium.storage.store_batch(&self.protium.object, &self.transactions);
+        if let Err(err) = stored {
+            self.protium.poisoned = true;
+            return Err(err);
+        }
+
+        self.protium.notify(&self.transactions);
+        Ok(())
+    }
 }
 
 /// A collection of acceptable `Transaction` types corresponding to a packable type `T`.
@@ -113,8 +411,13 @@ impl<T: Packable> Transactions<T> {
     ///
     /// # Panics
     ///
-    /// Panics if a type with the same `Transaction::key()` has already been registered.
+    /// Panics if a type with the same `Transaction::key()` has already been registered, or if the
+    /// key is `BATCH_TRANSACTION_KEY`.
     pub fn register<R: Transaction<T>>(mut self) -> Transactions<T> {
+        if R::key() == BATCH_TRANSACTION_KEY {
+            panic!("Reserved transaction key {}", R::key());
+        }
+
         if self.transactions.contains_key(&R::key()) {
             panic!("Duplicated transaction key {}", R::key());
         }
@@ -135,8 +438,8 @@ impl<T: Packable> Transactions<T> {
     /// Unpacks an object and a list of transactions, applies the list of transactions to the
     /// object, then returns the updated object.
     ///
-    /// Returns `Err` if unpacking the object or the transactions fails, or if any of the packed
-    /// transactions types were unregistered.
+    /// Returns `Err` if unpacking the object or the transactions fails, if any of the packed
+    /// transactions types were unregistered, or if applying any of them panics.
     fn unpack(&self, object: PackedObject, transactions: Vec<PackedTransaction>)
         -> Result<T, Error>
     {
@@ -165,6 +468,16 @@ pub struct PackedObject(pub Vec<u8>);
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct PackedTransaction(pub TransactionKey, pub Vec<u8>);
 
+/// What a storage repaired while it was opened and last loaded, as returned by
+/// `Storage::recovery`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct StorageRecovery {
+    /// `true` if an object was promoted from a temporary file left behind by an interrupted write.
+    pub promoted_temp_file: bool,
+    /// The number of bytes of torn or corrupt data discarded by the last call to `load`.
+    pub discarded_bytes: u64,
+}
+
 pub trait Storage<T: Packable> {
     /// Fetches the packed object and its transactions from the implementation's storage.
     ///
@@ -179,6 +492,12 @@ pub trait Storage<T: Packable> {
     /// the client wants to record a new or default object.
     fn store_object(&mut self, object: &T) -> Result<(), Error>;
 
+    /// Returns `true` if the storage cannot store anything, e.g. because it was opened for
+    /// inspection only. `Protium` refuses to apply transactions to an object in such a storage.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
     /// Durably stores the packable object and/or its newly applied transaction. This is called
     /// called by `Protium::apply()`, whenever a transaction is applied.
     ///
@@ -188,12 +507,92 @@ pub trait Storage<T: Packable> {
     /// failure, so storing transactions is unnecessary.
     fn store_data<R: Transaction<T>>(&mut self, object: &T, transaction: &R)
         -> Result<(), Error>;
+
+    /// Durably stores the packable object and/or a batch of newly applied transactions, as a
+    /// unit. This is called by `Batch::commit()`, after every transaction in the batch has been
+    /// applied to `object`.
+    ///
+    /// If the system fails while the batch is being stored, the implementation must recover
+    /// either all of the transactions or none of them. The default implementation stores `object`
+    /// in place of the transactions.
+    fn store_batch(&mut self, object: &T, _: &[PackedTransaction]) -> Result<(), Error> {
+        self.store_object(object)
+    }
+
+    /// Makes everything stored so far durable, if the implementation defers doing so. This is
+    /// called by `Protium::flush()`.
+    ///
+    /// The default implementation does nothing, for storages that store everything durably
+    /// straight away.
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Describes what the implementation repaired while it was opened and last loaded. This is
+    /// called by `Protium::new_with_report()`, right after `load`.
+    ///
+    /// The default implementation reports that nothing was repaired.
+    fn recovery(&self) -> StorageRecovery {
+        StorageRecovery::default()
+    }
+}
+
+/// Loads the object from `storage` and replays its transactions, storing `T::default()` if the
+/// storage is uninitialized and writable.
+fn load_object<T: Packable + Default, S: Storage<T>>(storage: &mut S,
+                                                     transactions: &Transactions<T>)
+    -> Result<(T, RecoveryReport), Error>
+{
+    let loaded = try!(storage.load());
+    let recovery = storage.recovery();
+    let mut report = RecoveryReport {
+        created_default: loaded.is_none(),
+        promoted_temp_file: recovery.promoted_temp_file,
+        replayed_transactions: 0,
+        discarded_bytes: recovery.discarded_bytes,
+    };
+
+    let object = match loaded {
+        Some((object, tx)) => {
+            report.replayed_transactions = tx.len() as u64;
+            try!(transactions.unpack(object, tx))
+        },
+        None => {
+            let result = T::default();
+            if !storage.is_read_only() {
+                try!(storage.store_object(&result));
+            }
+            result
+        },
+    };
+
+    Ok((object, report))
 }
 
 #[inline]
 fn apply_transaction<T: Packable, R: Transaction<T>>(object: &mut T, data: &[u8])
     -> Result<(), Error>
 {
-    try!(R::unpack(data).map_err(|_| Error::TransactionUnpack)).apply(object);
-    Ok(())
+    let transaction = try!(R::unpack(data).map_err(|_| Error::TransactionUnpack));
+    catch_panic(|| {
+        transaction.apply(object);
+    })
+}
+
+/// Calls `f`, returning `Err(Error::Panicked)` if it panics.
+///
+/// Whatever `f` was changing is left as it was when the panic occurred, so the caller must not
+/// use it again until it has been rebuilt.
+fn catch_panic<R, F: FnOnce() -> R>(f: F) -> Result<R, Error> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        let message = match payload.downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => match payload.downcast_ref::<String>() {
+                Some(message) => message.clone(),
+                None => String::new(),
+            },
+        };
+
+        Error::Panicked(message)
+    })
 }
@@ -0,0 +1,173 @@
+use super::{Packable, PackedObject, PackedTransaction, Storage, Transaction};
+use compaction::{CompactionPolicy, CompactionStats, TransactionLimit};
+use disk;
+use error::Error;
+
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// A storage implementation that keeps the packed object and its transactions in memory, so
+/// nothing survives the process. Meant for tests, and for objects that need not be durable.
+///
+/// The storage is compacted according to its `CompactionPolicy`, exactly as a `FileStorage` would
+/// be: the log is measured as if each transaction were written to a file, so a policy compacts a
+/// `MemoryStorage` at the same points as it would a `FileStorage`. By default, it is compacted
+/// after every 16 transactions.
+///
+/// Cloning the storage copies what it holds, but not the object built from it, so a clone passed
+/// to `Protium::new` simulates a restart of the process.
+pub struct MemoryStorage<T: Packable> {
+    object: Option<PackedObject>,
+    transactions: Vec<PackedTransaction>,
+    log_bytes: u64,
+    compacted_at: Instant,
+    compaction: Arc<CompactionPolicy + Sync>,
+    marker: PhantomData<T>,
+}
+
+impl<T: Packable> MemoryStorage<T> {
+    /// Initialize an empty storage, compacted with the default `CompactionPolicy`.
+    pub fn new() -> MemoryStorage<T> {
+        MemoryStorage {
+            object: None,
+            transactions: vec![],
+            log_bytes: 0,
+            compacted_at: Instant::now(),
+            compaction: Arc::new(TransactionLimit::default()),
+            marker: PhantomData,
+        }
+    }
+
+    /// Initialize a storage that already holds `object` followed by `transactions`, as if they
+    /// had been stored by an earlier process.
+    pub fn with_contents(object: PackedObject, transactions: Vec<PackedTransaction>)
+        -> MemoryStorage<T>
+    {
+        let mut result = MemoryStorage::new();
+        result.log_bytes = transactions.iter()
+            .map(|packed| chunk_length(disk::transaction_payload_length(packed.1.len())))
+            .sum();
+        result.object = Some(object);
+        result.transactions = transactions;
+        result
+    }
+
+    /// Sets the policy that decides when the storage is compacted. The policy is shared with every
+    /// clone of the storage, so it must be `Sync` as well.
+    ///
+    /// Defaults to `TransactionLimit(16)`.
+    pub fn compaction<P: CompactionPolicy + Sync + 'static>(mut self, policy: P)
+        -> MemoryStorage<T>
+    {
+        self.compaction = Arc::new(policy);
+        self
+    }
+
+    /// Returns the packed object last stored, if any.
+    pub fn object(&self) -> Option<&PackedObject> {
+        self.object.as_ref()
+    }
+
+    /// Returns the packed transactions stored since the object was last stored.
+    pub fn transactions(&self) -> &[PackedTransaction] {
+        &self.transactions
+    }
+
+    /// Returns the current state of the transaction log, as seen by the compaction policy.
+    pub fn compaction_stats(&self) -> CompactionStats {
+        CompactionStats {
+            transaction_count: self.transactions.len() as u64,
+            log_bytes: self.log_bytes,
+            snapshot_bytes: self.object.as_ref().map_or(0, |object| object.0.len() as u64),
+            elapsed: self.compacted_at.elapsed(),
+        }
+    }
+
+    /// Returns `true` if the object must be stored in place of the next transactions.
+    fn needs_compact(&self) -> bool {
+        self.object.is_none() || self.compaction.should_compact(&self.compaction_stats())
+    }
+}
+
+impl<T: Packable> Clone for MemoryStorage<T> {
+    fn clone(&self) -> MemoryStorage<T> {
+        MemoryStorage {
+            object: self.object.clone(),
+            transactions: self.transactions.clone(),
+            log_bytes: self.log_bytes,
+            compacted_at: self.compacted_at,
+            compaction: self.compaction.clone(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Packable> Default for MemoryStorage<T> {
+    fn default() -> MemoryStorage<T> {
+        MemoryStorage::new()
+    }
+}
+
+impl<T: Packable> Storage<T> for MemoryStorage<T> {
+    fn load(&mut self) -> Result<Option<(PackedObject, Vec<PackedTransaction>)>, Error> {
+        self.compacted_at = Instant::now();
+
+        match self.object {
+            Some(ref object) => Ok(Some((object.clone(), self.transactions.clone()))),
+            None => Ok(None),
+        }
+    }
+
+    fn store_object(&mut self, object: &T) -> Result<(), Error> {
+        let packed = match object.pack() {
+            Ok(packed) => packed,
+            Err(()) => return Err(Error::ObjectPack),
+        };
+
+        self.object = Some(PackedObject(packed));
+        self.transactions.clear();
+        self.log_bytes = 0;
+        self.compacted_at = Instant::now();
+        Ok(())
+    }
+
+    fn store_data<R: Transaction<T>>(&mut self, object: &T, transaction: &R)
+        -> Result<(), Error>
+    {
+        if self.needs_compact() {
+            return self.store_object(object);
+        }
+
+        let packed = match transaction.pack() {
+            Ok(packed) => packed,
+            Err(()) => return Err(Error::TransactionPack),
+        };
+
+        self.log_bytes += chunk_length(disk::transaction_payload_length(packed.len()));
+        self.transactions.push(PackedTransaction(R::key(), packed));
+        Ok(())
+    }
+
+    fn store_batch(&mut self, object: &T, transactions: &[PackedTransaction])
+        -> Result<(), Error>
+    {
+        if transactions.is_empty() {
+            return Ok(());
+        }
+
+        if self.needs_compact() {
+            return self.store_object(object);
+        }
+
+        self.log_bytes += chunk_length(disk::batch_payload_length(transactions));
+        self.transactions.extend(transactions.iter().cloned());
+        Ok(())
+    }
+}
+
+/// Returns the number of bytes that a `FileStorage` would append to its log to store a chunk
+/// holding a payload of `payload_length` bytes.
+fn chunk_length(payload_length: usize) -> u64 {
+    (disk::chunk_header_length(disk::FORMAT_VERSION) + payload_length) as u64
+}
@@ -0,0 +1,285 @@
+//! Recovers what can be read from a storage file that is damaged partway through, e.g. by bad
+//! sectors, rather than only at its end.
+
+use super::BATCH_TRANSACTION_KEY;
+use disk::{self, ChunkRead};
+use error::Error;
+use verify::ChunkFault;
+
+use byteorder::{ByteOrder, LittleEndian};
+use std::cmp;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// The number of bytes read at a time while searching for the next chunk marker.
+const SEARCH_BLOCK_LENGTH: usize = 64 * 1024;
+
+/// A valid chunk of transactions read from a damaged file.
+struct RecoveredChunk {
+    /// The chunk's payload, re-encoded in the current format.
+    payload: Vec<u8>,
+    /// The number of transactions in the chunk.
+    count: u64,
+    /// The offset of the chunk after it.
+    next: u64,
+}
+
+/// A range of a damaged file that was skipped because no valid chunk could be read from it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SkippedRange {
+    /// The offset of the range from the start of the file.
+    pub offset: u64,
+    /// The length of the range. Reading resumed at `offset + length`, unless that is the end of
+    /// the file.
+    pub length: u64,
+    /// Why the chunk at `offset` is invalid.
+    pub fault: ChunkFault,
+}
+
+/// What was recovered from a damaged storage file by `Salvage::recover`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Salvage {
+    /// The damaged file, which is left untouched.
+    pub path: PathBuf,
+    /// The file holding everything recovered, in the current format.
+    pub recovered_path: PathBuf,
+    /// The file holding this report, as text.
+    pub report_path: PathBuf,
+    /// The format version of the damaged file.
+    pub version: u32,
+    /// The number of bytes occupied by the recovered packed object.
+    pub snapshot_bytes: u64,
+    /// The number of transactions recovered after the object.
+    pub transaction_count: u64,
+    /// The ranges of the damaged file that were skipped, in order.
+    pub skipped: Vec<SkippedRange>,
+}
+
+impl Salvage {
+    /// Reads the storage file at `path` like `load` does, but rather than stopping at the first
+    /// invalid chunk, skips forward until a valid chunk is found and resumes reading from there.
+    /// The object and every transaction read are written to a new file at `path` with
+    /// `.recovered` appended, and a description of what was skipped to `path` with
+    /// `.recovered.txt` appended. The damaged file itself is never modified, and no lock is taken.
+    ///
+    /// If the header of an invalid chunk is intact and a valid chunk follows where it says the
+    /// chunk ends, reading resumes there, so chunks held within its payload are never mistaken for
+    /// records. Otherwise, reading resumes at the next chunk marker that begins a valid chunk.
+    /// Files written before revision 3 of the format have no chunk markers, so everything after an
+    /// invalid chunk whose header is damaged is skipped.
+    ///
+    /// A chunk is only accepted once its checksum, which covers its length as well as its payload,
+    /// matches, and its payload is a well-formed transaction or batch. Even so, a damaged range
+    /// may happen to look like a valid chunk, and transactions that were lost in a skipped range
+    /// are missing from the recovered file. Inspect the report before replacing the damaged file
+    /// with the recovered one.
+    ///
+    /// Chunks larger than 1 GiB are treated as invalid; use `FileStorageOptions::salvage` to
+    /// recover a file written with a larger maximum chunk size.
+    ///
+    /// Returns `Err(Error::Corrupt)` if the file does not begin with a valid object, since the
+    /// transactions after it cannot be replayed without one. Returns `Err` if the file's header is
+    /// invalid or an IO error occurs.
+    pub fn recover<P: AsRef<Path>>(path: P) -> Result<Salvage, Error> {
+        recover_file(path.as_ref(), disk::DEFAULT_MAX_CHUNK_SIZE)
+    }
+}
+
+/// Recovers the storage file at `path` as `Salvage::recover` does, treating chunks larger than
+/// `max_length` as invalid.
+pub fn recover_file(path: &Path, max_length: u64) -> Result<Salvage, Error> {
+    let file = try!(File::open(path));
+    let version = match try!(disk::read_storage_header(&file, max_length)) {
+        Some(version) => version,
+        None => return Err(Error::Corrupt),
+    };
+
+    let start = disk::header_length(version);
+    let object = match try!(read_chunk(&file, start, version, max_length)) {
+        Ok(object) => object,
+        Err(_) => return Err(Error::Corrupt),
+    };
+
+    let mut salvage = Salvage {
+        path: path.to_path_buf(),
+        recovered_path: PathBuf::from(format!("{}.recovered", path.display())),
+        report_path: PathBuf::from(format!("{}.recovered.txt", path.display())),
+        version: version,
+        snapshot_bytes: object.len() as u64,
+        transaction_count: 0,
+        skipped: vec![],
+    };
+
+    let mut recovered = BufWriter::new(try!(create_file(&salvage.recovered_path)));
+    try!(recovered.write_all(&disk::encode_header()));
+    try!(recovered.write_all(&disk::encode_chunk(&object)));
+
+    let length = try!(file.metadata()).len();
+    let mut position = start + chunk_length(version, &object);
+    while position < length {
+        let fault = match try!(read_transactions(&file, position, version, max_length)) {
+            Ok(chunk) => {
+                try!(recovered.write_all(&disk::encode_chunk(&chunk.payload)));
+                salvage.transaction_count += chunk.count;
+                position = chunk.next;
+                continue;
+            },
+            Err(fault) => fault,
+        };
+
+        let resume = try!(resync(&file, position, fault, version, max_length, length));
+        salvage.skipped.push(SkippedRange {
+            offset: position,
+            length: resume - position,
+            fault: fault,
+        });
+        position = resume;
+    }
+
+    let recovered = try!(recovered.into_inner().map_err(|err| err.into_error()));
+    try!(recovered.sync_all());
+
+    let mut report = try!(create_file(&salvage.report_path));
+    try!(report.write_all(salvage.to_string().as_bytes()));
+    try!(report.sync_all());
+    Ok(salvage)
+}
+
+impl Display for Salvage {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        try!(writeln!(f, "Salvaged {} (format version {})", self.path.display(), self.version));
+        try!(writeln!(f, "Recovered file: {}", self.recovered_path.display()));
+        try!(writeln!(f, "Recovered object: {} bytes", self.snapshot_bytes));
+        try!(writeln!(f, "Recovered transactions: {}", self.transaction_count));
+
+        let skipped_bytes = self.skipped.iter().map(|range| range.length).sum::<u64>();
+        try!(writeln!(f, "Skipped: {} bytes in {} ranges", skipped_bytes, self.skipped.len()));
+
+        for range in &self.skipped {
+            try!(writeln!(f, "  offset {}, {} bytes: {:?}", range.offset, range.length,
+                          range.fault));
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the length of the chunk holding `payload` in a file of the given format version.
+fn chunk_length(version: u32, payload: &[u8]) -> u64 {
+    (disk::chunk_header_length(version) + payload.len()) as u64
+}
+
+/// Reads the chunk at `offset` in `file`, returning its payload or why it is invalid.
+fn read_chunk(mut file: &File, offset: u64, version: u32, max_length: u64)
+    -> Result<Result<Vec<u8>, ChunkFault>, Error>
+{
+    try!(file.seek(SeekFrom::Start(offset)));
+    match try!(disk::scan_chunk(file, version, max_length)) {
+        ChunkRead::Chunk(payload) => Ok(Ok(payload)),
+        ChunkRead::End => Ok(Err(ChunkFault::Truncated)),
+        ChunkRead::Fault(fault) => Ok(Err(fault)),
+    }
+}
+
+/// Reads the chunk at `offset` in `file` as a transaction or a batch of transactions.
+fn read_transactions(file: &File, offset: u64, version: u32, max_length: u64)
+    -> Result<Result<RecoveredChunk, ChunkFault>, Error>
+{
+    let payload = match try!(read_chunk(file, offset, version, max_length)) {
+        Ok(payload) => payload,
+        Err(fault) => return Ok(Err(fault)),
+    };
+
+    let next = offset + chunk_length(version, &payload);
+    let batch = payload.len() >= 4 && LittleEndian::read_u32(&payload) == BATCH_TRANSACTION_KEY;
+    let transactions = match disk::decode_transactions(payload, version) {
+        Some(transactions) => transactions,
+        None => return Ok(Err(ChunkFault::Malformed)),
+    };
+
+    let payload = if batch {
+        disk::encode_batch(&transactions)
+    } else {
+        disk::encode_transaction(transactions[0].0, &transactions[0].1)
+    };
+
+    Ok(Ok(RecoveredChunk {
+        payload: payload,
+        count: transactions.len() as u64,
+        next: next,
+    }))
+}
+
+/// Returns the offset at which reading resumes after the invalid chunk at `offset` in `file`,
+/// which is `length` bytes long, or `length` if no valid chunk follows it.
+fn resync(file: &File, offset: u64, fault: ChunkFault, version: u32, max_length: u64,
+          length: u64)
+    -> Result<u64, Error>
+{
+    if fault == ChunkFault::Checksum || fault == ChunkFault::Malformed {
+        if let Some(end) = try!(declared_end(file, offset, version)) {
+            if end == length || try!(read_transactions(file, end, version, max_length)).is_ok() {
+                return Ok(end);
+            }
+        }
+    }
+
+    if !disk::has_chunk_marker(version) {
+        return Ok(length);
+    }
+
+    let mut candidate = offset + 1;
+    while let Some(marker) = try!(find_marker(file, candidate, length)) {
+        if try!(read_transactions(file, marker, version, max_length)).is_ok() {
+            return Ok(marker);
+        }
+        candidate = marker + 1;
+    }
+
+    Ok(length)
+}
+
+/// Returns the offset at which the chunk at `offset` in `file` ends according to its header, or
+/// `None` if that is past the end of the file or the header is invalid.
+fn declared_end(mut file: &File, offset: u64, version: u32) -> Result<Option<u64>, Error> {
+    let mut header = vec![0; disk::chunk_header_length(version)];
+    try!(file.seek(SeekFrom::Start(offset)));
+    try!(file.read_exact(&mut header));
+
+    let end = match disk::decode_chunk_header(&header, version) {
+        Ok(payload_length) => offset.saturating_add(header.len() as u64)
+            .saturating_add(payload_length),
+        Err(_) => return Ok(None),
+    };
+
+    if end <= try!(file.metadata()).len() { Ok(Some(end)) } else { Ok(None) }
+}
+
+/// Returns the offset of the first chunk marker at or after `offset` in `file`, which is
+/// `length` bytes long, reading a block at a time.
+fn find_marker(mut file: &File, mut offset: u64, length: u64) -> Result<Option<u64>, Error> {
+    let marker = &disk::CHUNK_MARKER[..];
+    let mut block = vec![0; SEARCH_BLOCK_LENGTH];
+
+    while length.saturating_sub(offset) >= marker.len() as u64 {
+        let block_length = cmp::min(SEARCH_BLOCK_LENGTH as u64, length - offset) as usize;
+        try!(file.seek(SeekFrom::Start(offset)));
+        try!(file.read_exact(&mut block[..block_length]));
+
+        if let Some(index) = block[..block_length].windows(marker.len()).position(|w| w == marker) {
+            return Ok(Some(offset + index as u64));
+        }
+
+        // Markers that straddle the end of the block are found in the next one.
+        offset += (block_length - (marker.len() - 1)) as u64;
+    }
+
+    Ok(None)
+}
+
+/// Creates the file at `path`, replacing any file already there.
+fn create_file(path: &Path) -> Result<File, Error> {
+    Ok(try!(OpenOptions::new().write(true).create(true).truncate(true).open(path)))
+}
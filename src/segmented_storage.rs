@@ -0,0 +1,734 @@
+use super::{Packable, PackedObject, PackedTransaction, Storage, StorageRecovery, Transaction};
+use compaction::{CompactionPolicy, CompactionStats, TransactionLimit};
+use disk::{self, HEADER_LENGTH, LockMode};
+use error::Error;
+use sync::{SyncMode, Syncer};
+
+use byteorder::{ByteOrder, LittleEndian};
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+const MANIFEST_NAME: &str = "MANIFEST";
+const MANIFEST_TEMP_NAME: &str = "MANIFEST~";
+const LOCK_NAME: &str = "LOCK";
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+const SEGMENT_PREFIX: &str = "log-";
+
+/// A storage implementation that uses a directory of files to atomically and durably store a
+/// packable object.
+///
+/// Unlike `FileStorage`, which keeps the object and its transaction log in a single file that is
+/// rewritten in its entirety on every compaction, the directory holds:
+///
+/// * `snapshot-<n>`: the packed object, stored when the storage was last compacted.
+/// * `log-<n>`: numbered log segments holding the transactions applied since then, in order. Once
+///   a segment grows past the configured segment size, a new segment is started.
+/// * `MANIFEST`: the number of the current snapshot and of the first log segment that follows it.
+///   The manifest is replaced atomically, so it always refers to a complete snapshot.
+/// * `LOCK`: the file on which the inter-process lock is taken.
+///
+/// Each file uses the same header and chunk layout as `FileStorage`. Compaction writes a new
+/// snapshot, points the manifest at it, then deletes the old snapshot and log segments, or moves
+/// them into an archive directory if one is configured. Log segments are never rewritten.
+///
+/// As with `FileStorage`, a torn or corrupt chunk marks the end of the log. `load` truncates it
+/// away, along with any later log segments.
+pub struct SegmentedStorage<T: Packable> {
+    directory: PathBuf,
+    archive: Option<PathBuf>,
+    manifest: Option<Manifest>,
+    segment: Option<File>,
+    segment_id: u64,
+    segment_bytes: u64,
+    segment_size: u64,
+    needs_initial_compact: bool,
+    transaction_count: u64,
+    log_bytes: u64,
+    snapshot_bytes: u64,
+    compacted_at: Instant,
+    compaction: Box<CompactionPolicy>,
+    discarded_bytes: u64,
+    read_only: bool,
+    max_chunk_size: u64,
+    syncer: Syncer,
+    // Held only to keep the lock until the storage is dropped.
+    #[allow(dead_code)]
+    lock: Option<File>,
+    marker: PhantomData<T>,
+}
+
+/// The contents of the manifest: which snapshot is current, and which log segment follows it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Manifest {
+    snapshot: u64,
+    first_segment: u64,
+}
+
+/// Options that configure how a `SegmentedStorage` is opened and maintained.
+pub struct SegmentedStorageOptions {
+    compaction: Box<CompactionPolicy>,
+    lock: LockMode,
+    segment_size: u64,
+    archive: Option<PathBuf>,
+    sync: SyncMode,
+    max_chunk_size: u64,
+}
+
+impl SegmentedStorageOptions {
+    /// Initialize with the default options.
+    pub fn new() -> SegmentedStorageOptions {
+        SegmentedStorageOptions {
+            compaction: Box::new(TransactionLimit::default()),
+            lock: LockMode::Exclusive,
+            segment_size: 64 * 1024 * 1024,
+            archive: None,
+            sync: SyncMode::Data,
+            max_chunk_size: disk::DEFAULT_MAX_CHUNK_SIZE,
+        }
+    }
+
+    /// Sets the policy that decides when the storage is compacted.
+    ///
+    /// Defaults to `TransactionLimit(16)`.
+    pub fn compaction<P: CompactionPolicy + 'static>(mut self, policy: P)
+        -> SegmentedStorageOptions
+    {
+        self.compaction = Box::new(policy);
+        self
+    }
+
+    /// Sets the kind of lock held on the storage for as long as it is open.
+    ///
+    /// Defaults to `LockMode::Exclusive`.
+    pub fn lock(mut self, mode: LockMode) -> SegmentedStorageOptions {
+        self.lock = mode;
+        self
+    }
+
+    /// Sets the size in bytes after which a new log segment is started. A segment may exceed this
+    /// size by at most one transaction.
+    ///
+    /// Defaults to 64 MiB.
+    pub fn segment_size(mut self, bytes: u64) -> SegmentedStorageOptions {
+        self.segment_size = bytes;
+        self
+    }
+
+    /// Sets a directory into which snapshots and log segments are moved once they have been
+    /// compacted away, instead of being deleted. The directory must be on the same file system as
+    /// the storage.
+    ///
+    /// Defaults to no archive.
+    pub fn archive<P: AsRef<Path>>(mut self, directory: P) -> SegmentedStorageOptions {
+        self.archive = Some(PathBuf::from(directory.as_ref()));
+        self
+    }
+
+    /// Sets when the data written to the storage is synced to the disk. See `SyncMode` for the
+    /// guarantees that each mode provides.
+    ///
+    /// Defaults to `SyncMode::Data`.
+    pub fn sync(mut self, mode: SyncMode) -> SegmentedStorageOptions {
+        self.sync = mode;
+        self
+    }
+
+    /// Sets the largest chunk payload, in bytes, that the storage reads or writes. A packed object
+    /// or transaction larger than this fails to be stored with `Error::ChunkTooLarge`, and so does
+    /// loading a storage holding one, since its length prefix is most likely corrupt. This bounds
+    /// the memory allocated for a chunk before its checksum can be verified.
+    ///
+    /// Defaults to 1 GiB, so it must be raised to store larger objects.
+    pub fn max_chunk_size(mut self, bytes: u64) -> SegmentedStorageOptions {
+        self.max_chunk_size = bytes;
+        self
+    }
+
+    /// Creates a new storage object linked to the directory at `path` using these options.
+    ///
+    /// See `SegmentedStorage::new` for details.
+    ///
+    /// Returns `Err(Error::Locked)` if another storage holds a conflicting lock on `path`.
+    pub fn open<T: Packable, P: AsRef<Path>>(self, path: P)
+        -> Result<SegmentedStorage<T>, Error>
+    {
+        let directory = PathBuf::from(path.as_ref());
+        let read_only = self.lock != LockMode::Exclusive;
+
+        if !read_only {
+            try!(fs::create_dir_all(&directory));
+        }
+
+        let lock = try!(disk::acquire_lock(&directory.join(LOCK_NAME), self.lock));
+
+        let mut result = SegmentedStorage {
+            directory: directory,
+            archive: self.archive,
+            manifest: None,
+            segment: None,
+            segment_id: 0,
+            segment_bytes: 0,
+            segment_size: self.segment_size,
+            needs_initial_compact: true,
+            transaction_count: 0,
+            log_bytes: 0,
+            snapshot_bytes: 0,
+            compacted_at: Instant::now(),
+            compaction: self.compaction,
+            discarded_bytes: 0,
+            read_only: read_only,
+            max_chunk_size: self.max_chunk_size,
+            syncer: Syncer::new(self.sync),
+            lock: lock,
+            marker: PhantomData,
+        };
+
+        result.manifest = try!(result.read_manifest());
+        result.segment_id = result.manifest.map_or(1, |manifest| manifest.first_segment);
+
+        if !read_only {
+            try!(result.remove_stale_files());
+        }
+
+        Ok(result)
+    }
+}
+
+impl Default for SegmentedStorageOptions {
+    fn default() -> SegmentedStorageOptions {
+        SegmentedStorageOptions::new()
+    }
+}
+
+impl<T: Packable> SegmentedStorage<T> {
+    /// Creates a new storage object linked to the directory at `path`, creating the directory if
+    /// it does not exist.
+    ///
+    /// Files left behind by an interrupted compaction, i.e. snapshots and log segments that the
+    /// manifest does not refer to, are removed when the storage is opened.
+    ///
+    /// The storage is opened with the default `SegmentedStorageOptions`.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<SegmentedStorage<T>, Error> {
+        SegmentedStorageOptions::new().open(path)
+    }
+
+    /// Returns a reference of the directory used to serve this storage.
+    pub fn path(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Returns the current state of the transaction log, as seen by the compaction policy.
+    pub fn compaction_stats(&self) -> CompactionStats {
+        CompactionStats {
+            transaction_count: self.transaction_count,
+            log_bytes: self.log_bytes,
+            snapshot_bytes: self.snapshot_bytes,
+            elapsed: self.compacted_at.elapsed(),
+        }
+    }
+
+    /// Returns the number of bytes of torn or corrupt data that were truncated from the end of the
+    /// log by the last call to `load`.
+    pub fn discarded_bytes(&self) -> u64 {
+        self.discarded_bytes
+    }
+
+    fn snapshot_path(&self, id: u64) -> PathBuf {
+        self.directory.join(format!("{}{:020}", SNAPSHOT_PREFIX, id))
+    }
+
+    fn segment_path(&self, id: u64) -> PathBuf {
+        self.directory.join(format!("{}{:020}", SEGMENT_PREFIX, id))
+    }
+
+    /// Reads the manifest, returning `Ok(None)` if the storage has never been written to.
+    ///
+    /// Returns `Err(Error::Corrupt)` if the manifest exists but is invalid, since it is only ever
+    /// replaced atomically.
+    fn read_manifest(&self) -> Result<Option<Manifest>, Error> {
+        let file = match File::open(self.directory.join(MANIFEST_NAME)) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let version = match try!(disk::read_header(&file)) {
+            Some(version) => version,
+            None => return Err(Error::Corrupt),
+        };
+
+        match try!(disk::read_chunk(&file, version, self.max_chunk_size)) {
+            Some(ref payload) if payload.len() == 16 => Ok(Some(Manifest {
+                snapshot: LittleEndian::read_u64(&payload[0..8]),
+                first_segment: LittleEndian::read_u64(&payload[8..16]),
+            })),
+            _ => Err(Error::Corrupt),
+        }
+    }
+
+    /// Durably replaces the manifest.
+    fn write_manifest(&self, manifest: Manifest) -> Result<(), Error> {
+        let mut payload = vec![0; 16];
+        LittleEndian::write_u64(&mut payload[0..8], manifest.snapshot);
+        LittleEndian::write_u64(&mut payload[8..16], manifest.first_segment);
+
+        let temp_path = self.directory.join(MANIFEST_TEMP_NAME);
+        let base_path = self.directory.join(MANIFEST_NAME);
+        try!(write_file(&temp_path, &payload, &self.syncer));
+        try!(fs::rename(&temp_path, &base_path));
+        self.syncer.sync_directory(&base_path)
+    }
+
+    /// Removes the snapshots and log segments that the manifest does not refer to, as well as a
+    /// leftover temporary manifest.
+    ///
+    /// Snapshots and log segments that precede the manifest are retired, in case they were not
+    /// yet archived. Those that follow it were written by an interrupted compaction and are simply
+    /// deleted.
+    fn remove_stale_files(&self) -> Result<(), Error> {
+        let mut changed = false;
+        let temp_path = self.directory.join(MANIFEST_TEMP_NAME);
+
+        if fs::metadata(&temp_path).is_ok() {
+            try!(fs::remove_file(&temp_path));
+            changed = true;
+        }
+
+        for entry in try!(fs::read_dir(&self.directory)) {
+            let path = try!(entry).path();
+            let name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => name.to_owned(),
+                None => continue,
+            };
+
+            let stale = match (self.manifest, parse_id(&name, SNAPSHOT_PREFIX)) {
+                (Some(manifest), Some(id)) if id < manifest.snapshot => Some(true),
+                (Some(manifest), Some(id)) if id > manifest.snapshot => Some(false),
+                (None, Some(_)) => Some(false),
+                _ => None,
+            };
+
+            let stale = stale.or_else(|| match (self.manifest, parse_id(&name, SEGMENT_PREFIX)) {
+                (Some(manifest), Some(id)) if id < manifest.first_segment => Some(true),
+                (None, Some(_)) => Some(false),
+                _ => None,
+            });
+
+            match stale {
+                Some(true) => try!(self.retire(&path)),
+                Some(false) => try!(fs::remove_file(&path)),
+                None => continue,
+            }
+
+            changed = true;
+        }
+
+        if changed {
+            try!(self.syncer.sync_directory(&temp_path));
+        }
+
+        Ok(())
+    }
+
+    /// Moves a snapshot or log segment that has been compacted away into the archive directory,
+    /// or deletes it if there is none.
+    fn retire(&self, path: &Path) -> Result<(), Error> {
+        let archive = match self.archive {
+            Some(ref archive) => archive,
+            None => {
+                try!(fs::remove_file(path));
+                return Ok(());
+            },
+        };
+
+        try!(fs::create_dir_all(archive));
+        let archived_path = archive.join(path.file_name().unwrap());
+        try!(fs::rename(path, &archived_path));
+        self.syncer.sync_directory(&archived_path)
+    }
+
+    /// Reads the object from the current snapshot.
+    fn read_snapshot(&self, manifest: Manifest) -> Result<Option<PackedObject>, Error> {
+        let file = match File::open(self.snapshot_path(manifest.snapshot)) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let version = match try!(disk::read_header(&file)) {
+            Some(version) => version,
+            None => return Err(Error::Corrupt),
+        };
+
+        match try!(disk::read_chunk(&file, version, self.max_chunk_size)) {
+            Some(data) => Ok(Some(PackedObject(data))),
+            None => Err(Error::Corrupt),
+        }
+    }
+
+    /// Reads every valid transaction from the log segments following the current snapshot,
+    /// truncating the log after the last valid transaction.
+    fn read_segments(&mut self, manifest: Manifest) -> Result<Vec<PackedTransaction>, Error> {
+        let mut transactions = vec![];
+        let mut id = manifest.first_segment;
+        self.segment = None;
+        self.segment_id = id;
+        self.segment_bytes = 0;
+
+        loop {
+            let path = self.segment_path(id);
+            let file = match OpenOptions::new().read(true).append(!self.read_only).open(&path) {
+                Ok(file) => file,
+                Err(ref err) if err.kind() == ErrorKind::NotFound => break,
+                Err(err) => return Err(err.into()),
+            };
+
+            let file_length = try!(file.metadata()).len();
+
+            // A segment whose header is torn was being created when the system crashed, so it
+            // holds no transactions and is discarded below.
+            let version = match try!(disk::read_header(&file)) {
+                Some(version) => version,
+                None => break,
+            };
+
+            let mut valid_length = HEADER_LENGTH as u64;
+            while let Some(payload) = try!(disk::read_chunk(&file, version, self.max_chunk_size)) {
+                let length = (disk::chunk_header_length(version) + payload.len()) as u64;
+                let chunk_transactions = match disk::decode_transactions(payload, version) {
+                    Some(chunk_transactions) => chunk_transactions,
+                    None => break,
+                };
+
+                valid_length += length;
+                self.transaction_count += chunk_transactions.len() as u64;
+                self.log_bytes += length;
+                transactions.extend(chunk_transactions);
+            }
+
+            self.segment_id = id;
+            self.segment_bytes = valid_length;
+
+            if valid_length < file_length {
+                self.discarded_bytes += file_length - valid_length;
+                if !self.read_only {
+                    try!(file.set_len(valid_length));
+                    try!(self.syncer.sync_file(&file));
+                }
+
+                id += 1;
+                try!(self.keep_segment(file, version));
+                break;
+            }
+
+            id += 1;
+            try!(self.keep_segment(file, version));
+        }
+
+        // Any segment after the end of the log follows a torn or missing segment, so none of its
+        // transactions can be applied.
+        try!(self.discard_segments_from(id));
+        Ok(transactions)
+    }
+
+    /// Returns the number of the last log segment following the current snapshot, or of the first
+    /// if there are none.
+    fn last_segment_id(&self) -> Result<u64, Error> {
+        let mut result = self.manifest.map_or(1, |manifest| manifest.first_segment);
+
+        for entry in try!(fs::read_dir(&self.directory)) {
+            let entry = try!(entry);
+            if let Some(id) = entry.file_name().to_str().and_then(|name| {
+                parse_id(name, SEGMENT_PREFIX)
+            }) {
+                result = cmp::max(result, id);
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Keeps the last loaded log segment open so that transactions are appended to it, unless it
+    /// was written by an earlier revision of the format, in which case a new segment is started.
+    fn keep_segment(&mut self, file: File, version: u32) -> Result<(), Error> {
+        if version != disk::FORMAT_VERSION {
+            self.segment = None;
+            self.segment_id += 1;
+            return Ok(());
+        }
+
+        if !self.read_only {
+            try!(self.syncer.track(&file));
+        }
+
+        self.segment = Some(file);
+        Ok(())
+    }
+
+    /// Returns `true` if the object must be stored in place of the next transactions.
+    fn needs_compact(&self) -> bool {
+        self.manifest.is_none() || self.needs_initial_compact ||
+            self.compaction.should_compact(&self.compaction_stats())
+    }
+
+    /// Appends a chunk holding `count` transactions to the last log segment, syncing it as the
+    /// `SyncMode` requires. A new segment is started first if the last one is full.
+    fn append(&mut self, chunk: &[u8], count: u64) -> Result<(), Error> {
+        if self.segment.is_some() && self.segment_bytes >= self.segment_size {
+            self.segment = None;
+            self.segment_id += 1;
+        }
+
+        if self.segment.is_none() {
+            try!(self.create_segment());
+        }
+
+        {
+            let file = self.segment.as_mut().unwrap();
+            try!(file.write_all(chunk));
+            try!(file.flush());
+        }
+
+        try!(self.syncer.written());
+
+        self.segment_bytes += chunk.len() as u64;
+        self.transaction_count += count;
+        self.log_bytes += chunk.len() as u64;
+        Ok(())
+    }
+
+    /// Discards every log segment numbered `id` or greater.
+    fn discard_segments_from(&mut self, id: u64) -> Result<(), Error> {
+        let mut changed = false;
+
+        for entry in try!(fs::read_dir(&self.directory)) {
+            let entry = try!(entry);
+            let name = match entry.file_name().to_str() {
+                Some(name) => name.to_owned(),
+                None => continue,
+            };
+
+            match parse_id(&name, SEGMENT_PREFIX) {
+                Some(segment_id) if segment_id >= id => (),
+                _ => continue,
+            }
+
+            self.discarded_bytes += try!(entry.metadata()).len();
+            if !self.read_only {
+                try!(fs::remove_file(entry.path()));
+                changed = true;
+            }
+        }
+
+        if changed {
+            try!(self.syncer.sync_directory(&self.segment_path(id)));
+        }
+
+        Ok(())
+    }
+
+    /// Starts the log segment that transactions are next appended to.
+    fn create_segment(&mut self) -> Result<(), Error> {
+        let path = self.segment_path(self.segment_id);
+        let mut file = try!(OpenOptions::new().write(true).create(true).truncate(true)
+            .open(&path));
+        try!(file.write_all(&disk::encode_header()));
+        try!(file.flush());
+        try!(self.syncer.sync_file(&file));
+        try!(self.syncer.sync_directory(&path));
+        try!(self.syncer.track(&file));
+        self.segment = Some(file);
+        self.segment_bytes = HEADER_LENGTH as u64;
+        Ok(())
+    }
+}
+
+impl<T: Packable> Storage<T> for SegmentedStorage<T> {
+    fn load(&mut self) -> Result<Option<(PackedObject, Vec<PackedTransaction>)>, Error> {
+        self.discarded_bytes = 0;
+
+        // A writer in another process may have since compacted the storage, and a failed
+        // compaction may have replaced the manifest without recording it, so loading always starts
+        // from the latest manifest. If the snapshot it refers to has been removed in the meantime,
+        // the manifest has changed again.
+        let mut previous = None;
+        let (manifest, object) = loop {
+            self.manifest = try!(self.read_manifest());
+
+            let manifest = match self.manifest {
+                Some(manifest) => manifest,
+                None => return Ok(None),
+            };
+
+            match try!(self.read_snapshot(manifest)) {
+                Some(object) => break (manifest, object),
+                None if self.read_only && previous != Some(manifest) => previous = Some(manifest),
+                None => return Err(Error::Corrupt),
+            }
+        };
+
+        self.transaction_count = 0;
+        self.log_bytes = 0;
+        self.snapshot_bytes = object.0.len() as u64;
+        self.compacted_at = Instant::now();
+        let transactions = try!(self.read_segments(manifest));
+
+        // Now that the log ends with a valid chunk, transactions may be appended to it directly.
+        self.needs_initial_compact = false;
+        Ok(Some((object, transactions)))
+    }
+
+    fn store_object(&mut self, object: &T) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        let packed = match object.pack() {
+            Ok(packed) => packed,
+            Err(()) => return Err(Error::ObjectPack),
+        };
+        try!(disk::check_chunk_length(packed.len() as u64, self.max_chunk_size));
+
+        // The new snapshot is not referred to until the manifest is replaced, so a crash at any
+        // point before then leaves the old snapshot and log intact. The new snapshot is removed
+        // when the storage is next opened.
+        // Until the log has been loaded, it is unknown how many segments follow the snapshot.
+        if self.needs_initial_compact {
+            self.segment_id = try!(self.last_segment_id());
+        }
+
+        let manifest = Manifest {
+            snapshot: self.manifest.map_or(1, |manifest| manifest.snapshot + 1),
+            first_segment: self.segment_id + 1,
+        };
+        try!(write_file(&self.snapshot_path(manifest.snapshot), &packed, &self.syncer));
+        try!(self.write_manifest(manifest));
+
+        let old_manifest = self.manifest;
+        let last_segment = self.segment_id;
+        self.manifest = Some(manifest);
+        self.segment = None;
+        self.syncer.discard();
+        self.segment_id = manifest.first_segment;
+        self.segment_bytes = 0;
+        self.transaction_count = 0;
+        self.log_bytes = 0;
+        self.snapshot_bytes = packed.len() as u64;
+        self.compacted_at = Instant::now();
+        self.needs_initial_compact = false;
+
+        // A crash while retiring the old files is harmless, since they precede the manifest and
+        // are retired when the storage is next opened.
+        if let Some(old_manifest) = old_manifest {
+            try!(self.retire(&self.snapshot_path(old_manifest.snapshot)));
+
+            for id in old_manifest.first_segment..last_segment + 1 {
+                let path = self.segment_path(id);
+                if fs::metadata(&path).is_ok() {
+                    try!(self.retire(&path));
+                }
+            }
+
+            try!(self.syncer.sync_directory(&self.directory.join(MANIFEST_NAME)));
+        }
+
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if self.read_only {
+            return Ok(());
+        }
+
+        // Nothing has been synced, not even the last compaction, so every file the manifest refers
+        // to is synced.
+        if let (SyncMode::None, Some(manifest)) = (self.syncer.mode(), self.manifest) {
+            try!(disk::sync_file(&self.snapshot_path(manifest.snapshot)));
+
+            for id in manifest.first_segment..self.segment_id + 1 {
+                let path = self.segment_path(id);
+                if fs::metadata(&path).is_ok() {
+                    try!(disk::sync_file(&path));
+                }
+            }
+
+            let manifest_path = self.directory.join(MANIFEST_NAME);
+            try!(disk::sync_file(&manifest_path));
+            try!(disk::sync_directory(&manifest_path));
+        }
+
+        self.syncer.flush()
+    }
+
+    fn recovery(&self) -> StorageRecovery {
+        StorageRecovery { promoted_temp_file: false, discarded_bytes: self.discarded_bytes }
+    }
+
+    fn store_data<R: Transaction<T>>(&mut self, object: &T, transaction: &R)
+        -> Result<(), Error>
+    {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        if self.needs_compact() {
+            return self.store_object(object);
+        }
+
+        let packed = match transaction.pack() {
+            Ok(packed) => packed,
+            Err(()) => return Err(Error::TransactionPack),
+        };
+
+        let payload = disk::encode_transaction(R::key(), &packed);
+        try!(disk::check_chunk_length(payload.len() as u64, self.max_chunk_size));
+        self.append(&disk::encode_chunk(&payload), 1)
+    }
+
+    fn store_batch(&mut self, object: &T, transactions: &[PackedTransaction])
+        -> Result<(), Error>
+    {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        if transactions.is_empty() {
+            return Ok(());
+        }
+
+        if self.needs_compact() {
+            return self.store_object(object);
+        }
+
+        let payload = disk::encode_batch(transactions);
+        try!(disk::check_chunk_length(payload.len() as u64, self.max_chunk_size));
+        self.append(&disk::encode_chunk(&payload), transactions.len() as u64)
+    }
+}
+
+/// Writes a new file at `path` holding a header and a single chunk wrapping `payload`, then syncs
+/// it unless the `SyncMode` never syncs.
+fn write_file(path: &Path, payload: &[u8], syncer: &Syncer) -> Result<(), Error> {
+    let mut file = try!(OpenOptions::new().write(true).create(true).truncate(true).open(path));
+    try!(file.write_all(&disk::encode_header()));
+    try!(file.write_all(&disk::encode_chunk(payload)));
+    try!(file.flush());
+    syncer.sync_file(&file)
+}
+
+/// Returns the number in a file name made of `prefix` followed by a zero-padded number.
+fn parse_id(name: &str, prefix: &str) -> Option<u64> {
+    if !name.starts_with(prefix) || name.len() != prefix.len() + 20 {
+        return None;
+    }
+
+    name[prefix.len()..].parse().ok()
+}
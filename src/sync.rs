@@ -0,0 +1,214 @@
+//! Decides when the data written by a storage is synced to the disk.
+
+use disk;
+use error::Error;
+
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// The shortest interval at which `SyncMode::Periodic` syncs.
+const MIN_SYNC_INTERVAL: Duration = Duration::from_millis(1);
+
+/// The ways in which a storage can make the transactions it stores durable.
+///
+/// Whatever the mode, every transaction is handed to the operating system before
+/// `Protium::apply` returns, so none are lost if only the process crashes. The modes differ in
+/// what survives a crash of the whole system, e.g. a power loss. Since every chunk is checksummed,
+/// in every mode but `None` the storage recovers the object as of some prefix of the transactions
+/// applied to it; transactions that were lost are always the most recent ones. `None` makes no
+/// such guarantee.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SyncMode {
+    /// Syncs the file's data and all of its metadata after every transaction, so each one is
+    /// durable before `Protium::apply` returns.
+    All,
+    /// Syncs the file's data, and only the metadata needed to read it back, after every
+    /// transaction, so each one is durable before `Protium::apply` returns. This skips updating
+    /// metadata such as the modification time, which is usually cheaper than `All`.
+    Data,
+    /// Syncs the file's data in a background thread, once per the given interval, if any
+    /// transactions have been stored since. Transactions stored within the last interval before a
+    /// crash may be lost. Intervals shorter than 1 ms are raised to 1 ms, so that the thread never
+    /// syncs continuously.
+    Periodic(Duration),
+    /// Syncs the file's data after every given number of transactions. Up to that many
+    /// transactions, less one, may be lost in a crash.
+    Transactions(u64),
+    /// Never syncs anything, not even during compaction, unless explicitly flushed, in which case
+    /// every file of the storage is synced. A crash may lose any number of transactions, not
+    /// necessarily the most recent ones, or even the whole storage, which may then fail to load
+    /// with `Error::Corrupt`. Meant for tests and for caches that can be rebuilt from elsewhere.
+    None,
+}
+
+/// The file that is being appended to, shared with the background thread of `SyncMode::Periodic`.
+struct SyncState {
+    file: Option<File>,
+    pending: u64,
+    error: Option<io::Error>,
+}
+
+impl SyncState {
+    /// Syncs the data appended to the file since it was last synced.
+    fn flush(&mut self) -> io::Result<()> {
+        if self.pending > 0 {
+            if let Some(ref file) = self.file {
+                try!(file.sync_data());
+            }
+
+            self.pending = 0;
+        }
+
+        Ok(())
+    }
+}
+
+/// Syncs the files of a storage according to a `SyncMode`.
+///
+/// The storage calls `track` with each file that it starts appending to, then calls `written`
+/// after each append.
+pub struct Syncer {
+    mode: SyncMode,
+    state: Arc<Mutex<SyncState>>,
+    background: Option<(Sender<()>, JoinHandle<()>)>,
+}
+
+impl Syncer {
+    /// Initialize with no file being appended to.
+    pub fn new(mode: SyncMode) -> Syncer {
+        Syncer {
+            mode: mode,
+            state: Arc::new(Mutex::new(SyncState { file: None, pending: 0, error: None })),
+            background: None,
+        }
+    }
+
+    /// Returns the mode that decides when files are synced.
+    pub fn mode(&self) -> SyncMode {
+        self.mode
+    }
+
+    /// Starts appending to `file`, first syncing whatever was appended to the previous file.
+    pub fn track(&mut self, file: &File) -> Result<(), Error> {
+        try!(self.flush());
+        let file = try!(file.try_clone());
+        self.state.lock().unwrap().file = Some(file);
+
+        if let SyncMode::Periodic(interval) = self.mode {
+            if self.background.is_none() {
+                self.background = Some(spawn_background(self.state.clone(), interval));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stops appending to the tracked file without syncing it, since its contents have been
+    /// superseded.
+    pub fn discard(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.file = None;
+        state.pending = 0;
+    }
+
+    /// Records that a chunk was appended to the tracked file, syncing it if the mode requires.
+    ///
+    /// Returns `Err` if the background thread failed to sync the file since this was last called.
+    pub fn written(&mut self) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(err) = state.error.take() {
+            return Err(err.into());
+        }
+
+        state.pending += 1;
+
+        match self.mode {
+            SyncMode::All => {
+                if let Some(ref file) = state.file {
+                    try!(file.sync_all());
+                }
+
+                state.pending = 0;
+            },
+            SyncMode::Data => try!(state.flush()),
+            SyncMode::Transactions(count) if state.pending >= count => try!(state.flush()),
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    /// Syncs whatever has been appended to the tracked file since it was last synced.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(err) = state.error.take() {
+            return Err(err.into());
+        }
+
+        try!(state.flush());
+        Ok(())
+    }
+
+    /// Syncs a file that has just been written in its entirety, unless the mode never syncs.
+    pub fn sync_file(&self, file: &File) -> Result<(), Error> {
+        if self.mode != SyncMode::None {
+            try!(file.sync_all());
+        }
+
+        Ok(())
+    }
+
+    /// Syncs the directory containing `path`, unless the mode never syncs.
+    pub fn sync_directory(&self, path: &Path) -> Result<(), Error> {
+        if self.mode != SyncMode::None {
+            try!(disk::sync_directory(path));
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        // Dropping the sender wakes the background thread, which then exits.
+        if let Some((sender, handle)) = self.background.take() {
+            drop(sender);
+            let _ = handle.join();
+        }
+
+        // Transactions that were deferred are synced now, so that closing the storage cleanly
+        // never loses any. Errors cannot be reported from here.
+        if self.mode != SyncMode::None {
+            let _ = self.flush();
+        }
+    }
+}
+
+/// Starts a thread that syncs the tracked file once per `interval`, until the returned sender is
+/// dropped.
+fn spawn_background(state: Arc<Mutex<SyncState>>, interval: Duration)
+    -> (Sender<()>, JoinHandle<()>)
+{
+    let (sender, receiver) = mpsc::channel();
+    let interval = cmp::max(interval, MIN_SYNC_INTERVAL);
+
+    let handle = thread::spawn(move || {
+        while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(interval) {
+            let mut state = state.lock().unwrap();
+
+            if let Err(err) = state.flush() {
+                state.error = Some(err);
+            }
+        }
+    });
+
+    (sender, handle)
+}
@@ -0,0 +1,262 @@
+use super::{
+    BATCH_TRANSACTION_KEY, Packable, PackedObject, PackedTransaction, TransactionKey, Transactions
+};
+use disk::{self, ChunkRead};
+use error::Error;
+
+use byteorder::{ByteOrder, LittleEndian};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Seek};
+use std::path::Path;
+
+/// The reasons for which a chunk can be invalid.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChunkFault {
+    /// The file ends partway through the chunk, e.g. because the system failed while it was being
+    /// written.
+    Truncated,
+    /// The chunk's checksum does not match its length and payload.
+    Checksum,
+    /// The chunk does not begin with the chunk marker, so whatever is there is not a chunk.
+    Marker,
+    /// The chunk's length exceeds the maximum chunk size, so its length is most likely corrupt.
+    TooLarge(u64),
+    /// The chunk is intact, but its payload is not a valid transaction or batch of transactions.
+    Malformed,
+}
+
+/// The first invalid chunk of a file, after which nothing more can be read.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BadChunk {
+    /// The offset of the chunk from the start of the file.
+    pub offset: u64,
+    /// Why the chunk is invalid.
+    pub fault: ChunkFault,
+}
+
+/// A description of the contents of a storage file, as returned by `FileStorage::verify`.
+#[derive(Debug)]
+pub struct VerifyReport {
+    /// The length of the file, or `0` if it does not exist.
+    pub file_bytes: u64,
+    /// The format version in the file's header, `0` if the file was written before the header
+    /// was introduced, or `None` if the file does not exist or its header is torn.
+    pub version: Option<u32>,
+    /// The number of bytes occupied by the packed object, or `None` if there is no valid object.
+    pub snapshot_bytes: Option<u64>,
+    /// The number of valid transactions following the object.
+    pub transaction_count: u64,
+    /// The number of valid transactions of each transaction key.
+    pub transaction_keys: BTreeMap<TransactionKey, u64>,
+    /// The length of the valid prefix of the file, which is what `load` keeps.
+    pub valid_bytes: u64,
+    /// The first invalid chunk, if any. Everything from it onwards is discarded by `load`.
+    pub bad_chunk: Option<BadChunk>,
+    /// `true` if a temporary file left behind by an interrupted compaction exists.
+    pub temp_file: bool,
+    /// The outcome of unpacking the object and replaying every valid transaction on it, if that
+    /// was requested and there is a valid object.
+    pub replay: Option<Result<(), Error>>,
+}
+
+impl VerifyReport {
+    /// Returns `true` if every byte of the file is valid, there is no temporary file, and replay,
+    /// if requested, succeeded.
+    pub fn is_clean(&self) -> bool {
+        if let Some(Err(_)) = self.replay {
+            return false;
+        }
+
+        self.valid_bytes == self.file_bytes && self.bad_chunk.is_none() && !self.temp_file
+    }
+}
+
+/// A record read from a storage file by `FileScan::read`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Record {
+    /// The packed object, whose chunk begins at the given offset.
+    Object(u64, PackedObject),
+    /// A packed transaction beginning at the given offset. This is the offset of its chunk, unless
+    /// it is part of a batch, in which case it is the offset of its entry within the batch.
+    Transaction(u64, PackedTransaction),
+}
+
+/// Every valid record of a storage file, in order, as read by `FileScan::read`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileScan {
+    /// The length of the file, or `0` if it does not exist.
+    pub file_bytes: u64,
+    /// The format version in the file's header, `0` if the file was written before the header
+    /// was introduced, or `None` if the file does not exist or its header is torn.
+    pub version: Option<u32>,
+    /// The valid records of the file: the object, if any, then each transaction.
+    pub records: Vec<Record>,
+    /// The length of the valid prefix of the file, which is what `load` keeps.
+    pub valid_bytes: u64,
+    /// The first invalid chunk, if any. Everything from it onwards is discarded by `load`.
+    pub bad_chunk: Option<BadChunk>,
+}
+
+impl FileScan {
+    /// Reads every valid record of the storage file at `path`, along with its offset, without
+    /// modifying the file or taking any lock. This is meant for tools that inspect storage files;
+    /// see `FileStorage::verify` for a summary that does not hold every record in memory.
+    ///
+    /// Chunks larger than the default maximum chunk size are reported as invalid; see
+    /// `FileStorageOptions::scan` to read a file written with another maximum.
+    ///
+    /// Returns `Err` if the file's header is invalid or an IO error occurs.
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<FileScan, Error> {
+        scan_file(path.as_ref(), disk::DEFAULT_MAX_CHUNK_SIZE)
+    }
+}
+
+/// Reads every valid record of the storage file at `path`, as `FileScan::read` does, treating
+/// chunks longer than `max_length` as invalid.
+pub fn scan_file(path: &Path, max_length: u64) -> Result<FileScan, Error> {
+    let mut records = vec![];
+    let walk = try!(walk_file(path, max_length, |record| records.push(record)));
+
+    Ok(FileScan {
+        file_bytes: walk.file_bytes,
+        version: walk.version,
+        records: records,
+        valid_bytes: walk.valid_bytes,
+        bad_chunk: walk.bad_chunk,
+    })
+}
+
+/// Walks every chunk of the storage file at `path`, without modifying it, and describes what it
+/// holds. If `transactions` is given, the object is unpacked and the transactions replayed on it.
+///
+/// Returns `Err` if the file's header is invalid or an IO error occurs.
+pub fn verify_file<T: Packable>(path: &Path, temp_path: &Path, max_length: u64,
+                                transactions: Option<&Transactions<T>>)
+    -> Result<VerifyReport, Error>
+{
+    let mut snapshot = None;
+    let mut replayed = vec![];
+    let mut transaction_keys = BTreeMap::new();
+    let mut transaction_count = 0;
+
+    let walk = try!(walk_file(path, max_length, |record| match record {
+        Record::Object(_, object) => snapshot = Some(object),
+        Record::Transaction(_, transaction) => {
+            *transaction_keys.entry(transaction.0).or_insert(0) += 1;
+            transaction_count += 1;
+
+            if transactions.is_some() {
+                replayed.push(transaction);
+            }
+        },
+    }));
+
+    let snapshot_bytes = snapshot.as_ref().map(|object| object.0.len() as u64);
+    let replay = match (transactions, snapshot) {
+        (Some(transactions), Some(object)) => {
+            Some(transactions.unpack(object, replayed).map(|_| ()))
+        },
+        _ => None,
+    };
+
+    Ok(VerifyReport {
+        file_bytes: walk.file_bytes,
+        version: walk.version,
+        snapshot_bytes: snapshot_bytes,
+        transaction_count: transaction_count,
+        transaction_keys: transaction_keys,
+        valid_bytes: walk.valid_bytes,
+        bad_chunk: walk.bad_chunk,
+        temp_file: fs::metadata(temp_path).is_ok(),
+        replay: replay,
+    })
+}
+
+/// What is left to report once every chunk of a file has been walked.
+struct Walk {
+    file_bytes: u64,
+    version: Option<u32>,
+    valid_bytes: u64,
+    bad_chunk: Option<BadChunk>,
+}
+
+/// Walks every chunk of the storage file at `path` until the first invalid one, passing a record
+/// of the object and then of each transaction to `record`.
+fn walk_file<F: FnMut(Record)>(path: &Path, max_length: u64, mut record: F)
+    -> Result<Walk, Error>
+{
+    let mut walk = Walk { file_bytes: 0, version: None, valid_bytes: 0, bad_chunk: None };
+
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(walk),
+        Err(err) => return Err(err.into()),
+    };
+
+    walk.file_bytes = try!(file.metadata()).len();
+    walk.version = try!(disk::read_storage_header(&file, max_length));
+
+    let version = match walk.version {
+        Some(version) => version,
+        None => {
+            if walk.file_bytes > 0 {
+                walk.bad_chunk = Some(BadChunk { offset: 0, fault: ChunkFault::Truncated });
+            }
+            return Ok(walk);
+        },
+    };
+
+    walk.valid_bytes = disk::header_length(version);
+    let header_length = disk::chunk_header_length(version) as u64;
+    let entry_header_length = disk::batch_entry_header_length(version) as u64;
+
+    let fault = match try!(disk::scan_chunk(&file, version, max_length)) {
+        ChunkRead::Chunk(payload) => {
+            record(Record::Object(walk.valid_bytes, PackedObject(payload)));
+            walk.valid_bytes = try!(file.stream_position());
+            None
+        },
+        ChunkRead::End => return Ok(walk),
+        ChunkRead::Fault(fault) => Some(fault),
+    };
+
+    let fault = match fault {
+        Some(fault) => Some(fault),
+        None => loop {
+            let payload = match try!(disk::scan_chunk(&file, version, max_length)) {
+                ChunkRead::Chunk(payload) => payload,
+                ChunkRead::End => break None,
+                ChunkRead::Fault(fault) => break Some(fault),
+            };
+
+            // A batch is laid out as its key, then each transaction preceded by its length.
+            let batch = payload.len() >= 4 &&
+                LittleEndian::read_u32(&payload[..4]) == BATCH_TRANSACTION_KEY;
+
+            let chunk_transactions = match disk::decode_transactions(payload, version) {
+                Some(chunk_transactions) => chunk_transactions,
+                None => break Some(ChunkFault::Malformed),
+            };
+
+            let mut offset = walk.valid_bytes;
+            if batch {
+                offset += header_length + 4;
+            }
+
+            for packed in chunk_transactions {
+                let length = (packed.1.len() + 4) as u64;
+                record(Record::Transaction(offset, packed));
+                offset += entry_header_length + length;
+            }
+
+            walk.valid_bytes = try!(file.stream_position());
+        },
+    };
+
+    if let Some(fault) = fault {
+        walk.bad_chunk = Some(BadChunk { offset: walk.valid_bytes, fault: fault });
+    }
+
+    Ok(walk)
+}
@@ -1,16 +1,15 @@
+use std::borrow::Cow;
 use std::collections::BTreeSet;
-use std::marker::PhantomData;
+use std::error::Error;
 
-use protium::{
-    Error, Packable, PackedObject, PackedTransaction, Storage, Transaction, TransactionKey
-};
+use protium::{Packable, Transaction, TransactionKey, Transactions};
 
 #[derive(Debug, Default, PartialEq)]
 pub struct Object(pub BTreeSet<u8>);
 
 impl Object {
-    fn insert(&mut self, value: u8) {
-        self.0.insert(value);
+    fn insert(&mut self, value: u8) -> bool {
+        self.0.insert(value)
     }
 
     fn remove(&mut self, value: u8) {
@@ -59,12 +58,15 @@ impl Packable for TransactionAdd {
 }
 
 impl Transaction<Object> for TransactionAdd {
+    // Whether the value was absent.
+    type Output = bool;
+
     fn key() -> TransactionKey {
         1
     }
 
-    fn apply(&self, object: &mut Object) {
-        object.insert(self.0);
+    fn apply(&self, object: &mut Object) -> bool {
+        object.insert(self.0)
     }
 }
 
@@ -85,69 +87,87 @@ impl Packable for TransactionRemove {
 }
 
 impl Transaction<Object> for TransactionRemove {
+    type Output = ();
+
     fn key() -> TransactionKey {
         2
     }
 
+    // Rejects removing an absent value, to test validation.
+    fn check(&self, object: &Object) -> Result<(), Box<Error + Send + Sync>> {
+        if object.0.contains(&self.0) {
+            Ok(())
+        } else {
+            Err(From::from("the value is absent"))
+        }
+    }
+
     fn apply(&self, object: &mut Object) {
         object.remove(self.0);
     }
 }
 
-#[derive(Debug, PartialEq)]
-pub struct SimpleStorage<T: Packable> {
-    object: Option<Vec<u8>>,
-    transactions: Vec<(TransactionKey, Vec<u8>)>,
-    packable: PhantomData<T>,
-}
+pub struct TransactionPanic(pub u8);
+
+impl Packable for TransactionPanic {
+    fn pack(&self) -> Result<Vec<u8>, ()> {
+        Ok(vec![self.0])
+    }
 
-impl<T: Packable> SimpleStorage<T> {
-    pub fn new(object: Option<Vec<u8>>, transactions: Vec<(TransactionKey, Vec<u8>)>)
-        -> SimpleStorage<T>
-    {
-        SimpleStorage {
-            object: object,
-            transactions: transactions,
-            packable: PhantomData,
+    fn unpack(data: &[u8]) -> Result<Self, ()> {
+        if data.len() == 1 {
+            Ok(TransactionPanic(data[0]))
+        } else {
+            Err(())
         }
     }
 }
 
-impl<T: Packable> Storage<T> for SimpleStorage<T> {
-    fn load(&mut self) -> Result<Option<(PackedObject, Vec<PackedTransaction>)>, Error> {
-        match self.object {
-            Some(ref object) => {
-                let object_data = PackedObject(object.clone());
-                let tx_data = self.transactions.iter().cloned()
-                    .map(|data| PackedTransaction(data.0, data.1))
-                    .collect();
-                Ok(Some((object_data, tx_data)))
-            },
-            None => Ok(None),
-        }
+impl Transaction<Object> for TransactionPanic {
+    type Output = ();
+
+    fn key() -> TransactionKey {
+        3
     }
 
-    fn store_object(&mut self, object: &T) -> Result<(), Error> {
-        self.object = match Packable::pack(object) {
-            Ok(data) => Some(data),
-            Err(()) => return Err(Error::ObjectPack),
-        };
+    // Panics after inserting the value, to test panic safety.
+    fn apply(&self, object: &mut Object) {
+        object.insert(self.0);
+        panic!("panicked applying {}", self.0);
+    }
+}
+
+// Registers every transaction type above.
+pub fn transactions() -> Transactions<Object> {
+    Transactions::new()
+        .register::<TransactionAdd>()
+        .register::<TransactionRemove>()
+        .register::<TransactionPanic>()
+}
+
+// Borrows its values until it is unpacked, to test batching transactions that are not `'static`.
+pub struct TransactionAddAll<'a>(pub Cow<'a, [u8]>);
 
-        Ok(())
+impl<'a> Packable for TransactionAddAll<'a> {
+    fn pack(&self) -> Result<Vec<u8>, ()> {
+        Ok(self.0.to_vec())
     }
 
-    fn store_data<R: Transaction<T>>(&mut self, object: &T, transaction: &R)
-        -> Result<(), Error>
-    {
-        if self.object.is_none() {
-            try!(self.store_object(object));
-        } else {
-            match Packable::pack(transaction) {
-                Ok(data) => self.transactions.push((R::key(), data)),
-                Err(()) => return Err(Error::TransactionPack),
-            }
-        }
+    fn unpack(data: &[u8]) -> Result<Self, ()> {
+        Ok(TransactionAddAll(Cow::Owned(data.to_vec())))
+    }
+}
 
-        Ok(())
+impl<'a> Transaction<Object> for TransactionAddAll<'a> {
+    type Output = ();
+
+    fn key() -> TransactionKey {
+        4
+    }
+
+    fn apply(&self, object: &mut Object) {
+        for &value in self.0.iter() {
+            object.insert(value);
+        }
     }
 }
@@ -0,0 +1,57 @@
+use common::Object;
+use protium::{CompactionPolicy, FileStorage, MemoryStorage, SegmentedStorage};
+use protium::compaction::{
+    AnyOf, CompactionStats, LogRatio, LogSizeLimit, Never, TimeLimit, TransactionLimit
+};
+use std::time::Duration;
+
+#[test]
+fn builtin_policies() {
+    let stats = stats(16, 100, 50, 10);
+    assert!(TransactionLimit(16).should_compact(&stats));
+    assert!(!TransactionLimit(17).should_compact(&stats));
+    assert!(LogSizeLimit(100).should_compact(&stats));
+    assert!(!LogSizeLimit(101).should_compact(&stats));
+    assert!(LogRatio(2.0).should_compact(&stats));
+    assert!(!LogRatio(2.5).should_compact(&stats));
+    assert!(TimeLimit(Duration::from_secs(10)).should_compact(&stats));
+    assert!(!TimeLimit(Duration::from_secs(11)).should_compact(&stats));
+    assert!(!Never.should_compact(&stats));
+}
+
+#[test]
+fn log_ratio_ignores_empty_log() {
+    assert!(!LogRatio(0.0).should_compact(&stats(0, 0, 0, 0)));
+    assert!(LogRatio(0.0).should_compact(&stats(1, 1, 0, 0)));
+}
+
+#[test]
+fn combined_policies() {
+    let policy = AnyOf(vec![Box::new(TransactionLimit(10)), Box::new(LogSizeLimit(1000))]);
+    assert!(policy.should_compact(&stats(10, 0, 0, 0)));
+    assert!(policy.should_compact(&stats(0, 1000, 0, 0)));
+    assert!(!policy.should_compact(&stats(9, 999, 0, 0)));
+
+    let closure = |stats: &CompactionStats| stats.transaction_count == 3;
+    assert!(closure.should_compact(&stats(3, 0, 0, 0)));
+    assert!(!closure.should_compact(&stats(4, 0, 0, 0)));
+}
+
+#[test]
+fn storages_are_send() {
+    fn assert_send<T: Send>() {}
+    assert_send::<FileStorage<Object>>();
+    assert_send::<MemoryStorage<Object>>();
+    assert_send::<SegmentedStorage<Object>>();
+}
+
+fn stats(transaction_count: u64, log_bytes: u64, snapshot_bytes: u64, elapsed: u64)
+    -> CompactionStats
+{
+    CompactionStats {
+        transaction_count: transaction_count,
+        log_bytes: log_bytes,
+        snapshot_bytes: snapshot_bytes,
+        elapsed: Duration::from_secs(elapsed),
+    }
+}
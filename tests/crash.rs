@@ -0,0 +1,28 @@
+use common::{transactions, TransactionAdd, TransactionRemove};
+use protium::compaction::TransactionLimit;
+use protium::crash::CrashTest;
+use protium::FileStorageOptions;
+use tempdir::TempDir;
+
+#[test]
+fn recovers_committed_prefix_at_every_crash_point() {
+    let temp_dir = TempDir::new("protium").unwrap();
+    let report = CrashTest::new(transactions)
+        .options(|| FileStorageOptions::new().compaction(TransactionLimit(3)))
+        .sector_size(16)
+        .apply(TransactionAdd(1))
+        .apply(TransactionAdd(2))
+        .step(|protium| {
+            let mut batch = protium.batch();
+            try!(batch.apply(TransactionAdd(3)));
+            try!(batch.apply(TransactionRemove(1)));
+            batch.commit()
+        })
+        .apply(TransactionAdd(4))
+        .apply(TransactionRemove(2))
+        .run(temp_dir.path())
+        .unwrap();
+
+    assert!(report.is_consistent(), "{:#?}", report.failures);
+    assert!(report.crash_points > 100);
+}
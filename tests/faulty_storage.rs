@@ -0,0 +1,41 @@
+use common::{transactions, Object, TransactionAdd};
+use protium::{Error, FaultyStorage, MemoryStorage, PackedTransaction, Protium, StorageOperation};
+use std::io::{self, ErrorKind};
+
+#[test]
+fn fails_scripted_calls() {
+    let storage = FaultyStorage::new(MemoryStorage::new())
+        .fail(StorageOperation::StoreData, 2, Error::Io(io::Error::new(ErrorKind::Other, "full")));
+    let mut protium = Protium::new(storage, transactions()).unwrap();
+    protium.apply(TransactionAdd(5)).unwrap();
+    match protium.apply(TransactionAdd(10)) {
+        Err(Error::Io(ref err)) if err.kind() == ErrorKind::Other => (),
+        _ => unreachable!(),
+    }
+    protium.reload().unwrap();
+    protium.apply(TransactionAdd(15)).unwrap();
+    assert_eq!(protium.storage().calls(StorageOperation::StoreData), 3);
+    assert_eq!(protium.storage().storage().transactions(), &[
+        PackedTransaction(1, vec![5]), PackedTransaction(1, vec![15])
+    ]);
+
+    let storage = FaultyStorage::new(MemoryStorage::<Object>::new())
+        .fail(StorageOperation::Load, 1, Error::Corrupt);
+    match Protium::new(storage, transactions()) {
+        Err(Error::Corrupt) => (),
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn drops_scripted_calls() {
+    let storage = FaultyStorage::new(MemoryStorage::new())
+        .drop_call(StorageOperation::StoreData, 1);
+    let mut protium = Protium::new(storage, transactions()).unwrap();
+    protium.apply(TransactionAdd(5)).unwrap();
+    protium.apply(TransactionAdd(10)).unwrap();
+    assert_eq!(*protium.object(), Object(vec![5, 10].iter().cloned().collect()));
+
+    let restarted = Protium::new(protium.storage().storage().clone(), transactions()).unwrap();
+    assert_eq!(*restarted.object(), Object(vec![10].iter().cloned().collect()));
+}
@@ -1,49 +1,214 @@
-use common::{Object, TransactionAdd};
-use protium::{Error, FileStorage, PackedObject, PackedTransaction, Storage, Transaction};
-use std::fs::{File, OpenOptions};
+use common::{transactions, Object, TransactionAdd, TransactionRemove};
+use protium::compaction::{LogSizeLimit, Never};
+use protium::{
+    BadChunk, ChunkFault, Error, FileStorage, FileStorageOptions, LockMode, PackedObject,
+    PackedTransaction, Protium, Salvage, SkippedRange, Storage, SyncMode, Transaction,
+    Transactions
+};
+use std::fs::{self, File, OpenOptions};
 use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
 use tempdir::TempDir;
 
+const HEADER: [u8; 16] = [80, 82, 79, 84, 73, 85, 77, 00, 03, 00, 00, 00, 00, 00, 00, 00];
+
 #[test]
 fn loads_pristine_file() {
     let result = write_and_load(&[
-        02u8, 00, 00, 00, 03, 04, 05, 00, 00, 00, 01, 00, 00, 00, 05, 05, 00, 00, 00, 02, 00, 00,
-        00, 04
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 145, 47, 63, 203, 03, 04, 245, 80, 67,
+        75, 05, 00, 00, 00, 00, 00, 00, 00, 174, 40, 81, 95, 01, 00, 00, 00, 05, 245, 80, 67, 75,
+        05, 00, 00, 00, 00, 00, 00, 00, 232, 98, 246, 111, 02, 00, 00, 00, 04
     ], false).unwrap().unwrap();
     assert_eq!(result.0, PackedObject(vec![3, 4]));
     assert_eq!(result.1, vec![PackedTransaction(1, vec![5]), PackedTransaction(2, vec![4])]);
 }
 
 #[test]
-fn ignores_corrupt_object() {
-    // Truncated chunk length:
-    assert_eq!(write_and_load(&[02u8, 00, 00], false).unwrap(), None);
-    // Mismatched chunk length:
-    assert_eq!(write_and_load(&[02u8, 00, 00, 00, 03], false).unwrap(), None);
+fn rejects_corrupt_object() {
+    // A header alone holds nothing:
+    assert_eq!(write_and_load(&[], false).unwrap(), None);
+
+    let corrupt: [&[u8]; 5] = [
+        // Missing chunk marker:
+        &[02u8, 00, 00, 00, 00, 00, 00, 00, 145, 47, 63, 203, 03, 04],
+        // Truncated chunk length:
+        &[245u8, 80, 67, 75, 02, 00, 00],
+        // Truncated chunk checksum:
+        &[245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 145, 47],
+        // Mismatched chunk length:
+        &[245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 145, 47, 63, 203, 03],
+        // Mismatched chunk checksum:
+        &[245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 145, 47, 63, 203, 03, 05],
+    ];
+
+    for data in &corrupt {
+        match write_and_load(data, false) {
+            Err(Error::Corrupt) => (),
+            _ => unreachable!(),
+        }
+    }
 }
 
 #[test]
 fn ignores_corrupt_transaction() {
     // Truncated chunk length:
     let result = write_and_load(&[
-        02u8, 00, 00, 00, 03, 04, 05, 00, 00, 00, 01, 00, 00, 00, 05, 03, 00, 00, 00, 02, 00, 00
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 145, 47, 63, 203, 03, 04, 245, 80, 67,
+        75, 05, 00, 00, 00, 00, 00, 00, 00, 174, 40, 81, 95, 01, 00, 00, 00, 05, 245, 80, 67, 75,
+        05, 00, 00
     ], false).unwrap().unwrap();
     assert_eq!(result.1, vec![PackedTransaction(1, vec![5])]);
 
     // Mismatched chunk length:
     let result = write_and_load(&[
-        02u8, 00, 00, 00, 03, 04, 05, 00, 00, 00, 01, 00, 00, 00, 05, 05, 00, 00, 00, 02, 00, 00,
-        00
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 145, 47, 63, 203, 03, 04, 245, 80, 67,
+        75, 05, 00, 00, 00, 00, 00, 00, 00, 174, 40, 81, 95, 01, 00, 00, 00, 05, 245, 80, 67, 75,
+        05, 00, 00, 00, 00, 00, 00, 00, 232, 98, 246, 111, 02, 00, 00, 00
+    ], false).unwrap().unwrap();
+    assert_eq!(result.1, vec![PackedTransaction(1, vec![5])]);
+
+    // Mismatched chunk checksum:
+    let result = write_and_load(&[
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 145, 47, 63, 203, 03, 04, 245, 80, 67,
+        75, 05, 00, 00, 00, 00, 00, 00, 00, 174, 40, 81, 95, 01, 00, 00, 00, 05, 245, 80, 67, 75,
+        05, 00, 00, 00, 00, 00, 00, 00, 232, 98, 246, 111, 02, 00, 00, 00, 05
     ], false).unwrap().unwrap();
     assert_eq!(result.1, vec![PackedTransaction(1, vec![5])]);
 }
 
+#[test]
+fn truncates_torn_tail_on_load() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test.db");
+    write_bytes(path.clone(), &with_header(&[
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 145, 47, 63, 203, 03, 04, 245, 80, 67,
+        75, 05, 00, 00, 00, 00, 00, 00, 00, 174, 40, 81, 95, 01, 00, 00, 00, 05, 245, 80, 67, 75,
+        05, 00, 00, 00, 00, 00, 00, 00, 232, 98, 246, 111, 02, 00, 00, 00, 05
+    ]));
+
+    let mut storage = file_storage(&temp_dir);
+    let (_, transactions) = storage.load().unwrap().unwrap();
+    assert_eq!(transactions, vec![PackedTransaction(1, vec![5])]);
+    assert_eq!(storage.discarded_bytes(), 21);
+    assert_eq!(read_bytes(&path).len(), 16 + 39);
+
+    // New transactions are appended directly after the last valid chunk:
+    let mut object = Object(vec![3, 4, 5].iter().cloned().collect());
+    let transaction = TransactionAdd(6);
+    transaction.apply(&mut object);
+    storage.store_data(&object, &transaction).unwrap();
+    drop(storage);
+
+    let mut storage = file_storage(&temp_dir);
+    let (_, transactions) = storage.load().unwrap().unwrap();
+    assert_eq!(transactions, vec![PackedTransaction(1, vec![5]), PackedTransaction(1, vec![6])]);
+    assert_eq!(storage.discarded_bytes(), 0);
+}
+
+#[test]
+fn quarantines_torn_tail_on_load() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test.db");
+    let data = with_header(&[
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 145, 47, 63, 203, 03, 04, 245, 80, 67,
+        75, 05, 00, 00, 00, 00, 00, 00, 00, 174, 40, 81, 95, 01, 00, 00, 00, 05, 245, 80, 67, 75,
+        05, 00, 00, 00, 00, 00, 00, 00, 232, 98, 246, 111, 02, 00, 00, 00, 05
+    ]);
+    write_bytes(path.clone(), &data);
+
+    let mut storage = FileStorageOptions::new().quarantine(true).open::<Object, _>(&path).unwrap();
+    storage.load().unwrap().unwrap();
+    assert_eq!(read_bytes(&path).len(), 16 + 39);
+    let sidecar_path = storage.quarantine_path().unwrap().to_path_buf();
+    assert_eq!(sidecar_path.parent(), Some(temp_dir.path()));
+    assert!(sidecar_path.to_str().unwrap().ends_with(".corrupt"));
+    assert_eq!(read_bytes(&sidecar_path), &data[16 + 39..]);
+
+    storage.load().unwrap().unwrap();
+    assert_eq!(storage.quarantine_path(), None);
+}
+
+#[test]
+fn keeps_torn_object_on_load() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test.db");
+    let data = with_header(&[
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 145, 47, 63, 203, 03
+    ]);
+    write_bytes(path.clone(), &data);
+
+    // The default object is never stored over a file that fails to load:
+    match Protium::new(file_storage(&temp_dir), transactions()) {
+        Err(Error::Corrupt) => (),
+        _ => unreachable!(),
+    }
+    assert_eq!(read_bytes(&path), data);
+}
+
 #[test]
 fn renames_temp_file_on_load() {
-    let result = write_and_load(&[02u8, 00, 00, 00, 03, 04], true).unwrap().unwrap();
+    let temp_dir = temp_dir();
+    write_bytes(temp_dir.path().join("test.db~"), &with_header(&[
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 145, 47, 63, 203, 03, 04
+    ]));
+    let mut storage = file_storage(&temp_dir);
+    let result = storage.load().unwrap().unwrap();
     assert_eq!(result.0, PackedObject(vec![3, 4]));
     assert_eq!(result.1, vec![]);
+    assert!(storage.recovery().promoted_temp_file);
+}
+
+#[test]
+fn renames_legacy_temp_file_on_load() {
+    // Earlier versions removed the base file before renaming the temporary file over it:
+    let temp_dir = temp_dir();
+    write_bytes(temp_dir.path().join("test.db~"), &[02u8, 00, 00, 00, 03, 04]);
+    let protium = Protium::new(file_storage(&temp_dir), transactions()).unwrap();
+    assert_eq!(*protium.object(), Object(vec![3, 4].iter().cloned().collect()));
+    assert!(protium.storage().recovery().promoted_temp_file);
+    assert!(fs::metadata(temp_dir.path().join("test.db~")).is_err());
+}
+
+#[test]
+fn discards_temp_file_when_base_exists() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test.db");
+    write_bytes(path.clone(), &with_header(&[
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 145, 47, 63, 203, 03, 04
+    ]));
+    write_bytes(temp_dir.path().join("test.db~"), &with_header(&[
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 38, 232, 106, 16, 01, 02
+    ]));
+    let result = file_storage(&temp_dir).load().unwrap().unwrap();
+    assert_eq!(result.0, PackedObject(vec![3, 4]));
+    assert!(fs::metadata(temp_dir.path().join("test.db~")).is_err());
+}
+
+#[test]
+fn discards_incomplete_temp_file() {
+    let temp_dir = temp_dir();
+    write_bytes(temp_dir.path().join("test.db~"), &with_header(&[
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 145, 47
+    ]));
+    assert_eq!(file_storage(&temp_dir).load().unwrap(), None);
+    assert!(fs::metadata(temp_dir.path().join("test.db")).is_err());
+    assert!(fs::metadata(temp_dir.path().join("test.db~")).is_err());
+}
+
+#[test]
+fn discards_temp_file_with_torn_header() {
+    let temp_dir = temp_dir();
+    let mut data = with_header(&[
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 145, 47, 63, 203, 03, 04
+    ]);
+    for byte in &mut data[..16] {
+        *byte = 0;
+    }
+    write_bytes(temp_dir.path().join("test.db~"), &data);
+    assert_eq!(file_storage(&temp_dir).load().unwrap(), None);
+    assert!(fs::metadata(temp_dir.path().join("test.db~")).is_err());
 }
 
 #[test]
@@ -53,7 +218,22 @@ fn store_object() {
     storage.store_object(&Object(vec![1, 2].iter().cloned().collect())).unwrap();
     let mut result = vec![];
     File::open(storage.path()).unwrap().read_to_end(&mut result).unwrap();
-    assert_eq!(result, vec![02u8, 00, 00, 00, 01, 02]);
+    assert_eq!(result, with_header(&[
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 38, 232, 106, 16, 01, 02
+    ]));
+}
+
+#[test]
+fn store_object_over_stale_temp_file() {
+    let temp_dir = temp_dir();
+    let mut storage = file_storage(&temp_dir);
+    write_bytes(temp_dir.path().join("test.db~"), &[0; 64]);
+    storage.store_object(&Object(vec![1, 2].iter().cloned().collect())).unwrap();
+    let result = read_bytes(storage.path());
+    assert_eq!(result, with_header(&[
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 38, 232, 106, 16, 01, 02
+    ]));
+    assert!(fs::metadata(temp_dir.path().join("test.db~")).is_err());
 }
 
 #[test]
@@ -67,7 +247,109 @@ fn store_data() {
     storage.store_data(&object, &transaction).unwrap();
     let mut result = vec![];
     File::open(storage.path()).unwrap().read_to_end(&mut result).unwrap();
-    assert_eq!(result, vec![02u8, 00, 00, 00, 01, 02, 05, 00, 00, 00, 01, 00, 00, 00, 03]);
+    assert_eq!(result, with_header(&[
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 38, 232, 106, 16, 01, 02, 245, 80, 67,
+        75, 05, 00, 00, 00, 00, 00, 00, 00, 155, 141, 50, 182, 01, 00, 00, 00, 03
+    ]));
+}
+
+#[test]
+fn store_batch() {
+    let temp_dir = temp_dir();
+    let mut storage = file_storage(&temp_dir);
+    let object = Object(vec![1, 2].iter().cloned().collect());
+    storage.store_object(&object).unwrap();
+    let batch = vec![PackedTransaction(1, vec![7]), PackedTransaction(2, vec![1])];
+    storage.store_batch(&object, &batch).unwrap();
+    assert_eq!(storage.compaction_stats().transaction_count, 2);
+    assert_eq!(read_bytes(storage.path()), with_header(&[
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 38, 232, 106, 16, 01, 02, 245, 80, 67,
+        75, 30, 00, 00, 00, 00, 00, 00, 00, 65, 175, 255, 169, 255, 255, 255, 255, 05, 00, 00, 00,
+        00, 00, 00, 00, 01, 00, 00, 00, 07, 05, 00, 00, 00, 00, 00, 00, 00, 02, 00, 00, 00, 01
+    ]));
+    drop(storage);
+
+    let result = file_storage(&temp_dir).load().unwrap().unwrap();
+    assert_eq!(result.1, batch);
+}
+
+#[test]
+fn ignores_chunk_longer_than_file() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test.db");
+    write_bytes(path.clone(), &with_header(&[
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 145, 47, 63, 203, 03, 04, 245, 80, 67,
+        75, 05, 00, 00, 00, 00, 00, 00, 00, 174, 40, 81, 95, 01, 00, 00, 00, 05, 245, 80, 67, 75,
+        255, 255, 255, 127, 00, 00, 00, 00, 00, 00, 00, 00, 02, 00, 00, 00, 04
+    ]));
+
+    let mut storage = file_storage(&temp_dir);
+    let (_, transactions) = storage.load().unwrap().unwrap();
+    assert_eq!(transactions, vec![PackedTransaction(1, vec![5])]);
+    assert_eq!(storage.discarded_bytes(), 21);
+}
+
+#[test]
+fn rejects_chunk_larger_than_maximum() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test.db");
+    let mut storage = FileStorageOptions::new().max_chunk_size(6).open(&path).unwrap();
+    let object = Object(vec![1, 2, 3].iter().cloned().collect());
+    storage.store_object(&object).unwrap();
+    storage.store_data(&object, &TransactionAdd(4)).unwrap();
+    let batch = vec![PackedTransaction(1, vec![4]), PackedTransaction(1, vec![5])];
+    match storage.store_batch(&object, &batch) {
+        Err(Error::ChunkTooLarge(30)) => (),
+        result => panic!("unexpected result {:?}", result),
+    }
+    drop(storage);
+
+    let mut storage = FileStorageOptions::new().max_chunk_size(2).open::<Object, _>(&path).unwrap();
+    match storage.load() {
+        Err(Error::ChunkTooLarge(3)) => (),
+        result => panic!("unexpected result {:?}", result),
+    }
+    assert_eq!(read_bytes(&path).len(), 16 + 19 + 21);
+}
+
+#[test]
+fn stores_with_each_sync_mode() {
+    let modes = [
+        SyncMode::All, SyncMode::Data, SyncMode::Periodic(Duration::from_millis(1)),
+        SyncMode::Periodic(Duration::from_millis(0)), SyncMode::Transactions(3), SyncMode::None
+    ];
+
+    for &mode in &modes {
+        let temp_dir = temp_dir();
+        let path = temp_dir.path().join("test.db");
+        let mut storage = FileStorageOptions::new().sync(mode).open(&path).unwrap();
+        let mut object = Object(vec![1].iter().cloned().collect());
+        storage.store_object(&object).unwrap();
+
+        for i in 2u8..40 {
+            object.0.insert(i);
+            storage.store_data(&object, &TransactionAdd(i)).unwrap();
+
+            if i % 10 == 0 {
+                thread::sleep(Duration::from_millis(2));
+                storage.flush().unwrap();
+            }
+        }
+        drop(storage);
+
+        let result = file_storage(&temp_dir).load().unwrap().unwrap();
+        assert_eq!(result.1.last(), Some(&PackedTransaction(1, vec![39])), "{:?}", mode);
+    }
+}
+
+#[test]
+fn ignores_torn_batch() {
+    let result = write_and_load(&[
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 38, 232, 106, 16, 01, 02, 245, 80, 67,
+        75, 30, 00, 00, 00, 00, 00, 00, 00, 65, 175, 255, 169, 255, 255, 255, 255, 05, 00, 00, 00,
+        00, 00, 00, 00, 01, 00, 00, 00, 07, 05, 00, 00, 00, 00, 00, 00, 00, 02, 00, 00, 00
+    ], false).unwrap().unwrap();
+    assert_eq!(result.1, vec![]);
 }
 
 #[test]
@@ -83,10 +365,489 @@ fn compact_many_transactions() {
     }
     let mut result = vec![];
     File::open(storage.path()).unwrap().read_to_end(&mut result).unwrap();
-    assert_eq!(result, vec![
-        17u8, 0, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 5, 0, 0, 0, 1, 0,
-        0, 0, 17
+    assert_eq!(result, with_header(&[
+        245u8, 80, 67, 75, 17, 00, 00, 00, 00, 00, 00, 00, 75, 114, 164, 241, 00, 01, 02, 03, 04,
+        05, 06, 07, 08, 09, 10, 11, 12, 13, 14, 15, 16, 245, 80, 67, 75, 05, 00, 00, 00, 00, 00, 00,
+        00, 211, 252, 139, 69, 01, 00, 00, 00, 17
+    ]));
+}
+
+#[test]
+fn compaction_policy() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test.db");
+    let mut storage = FileStorageOptions::new().compaction(Never).open::<Object, _>(&path).unwrap();
+    let mut object = Object::default();
+    storage.store_object(&object).unwrap();
+    for i in 0u8..20 {
+        let transaction = TransactionAdd(i);
+        transaction.apply(&mut object);
+        storage.store_data(&object, &transaction).unwrap();
+    }
+    assert_eq!(storage.compaction_stats().transaction_count, 20);
+    assert_eq!(storage.compaction_stats().log_bytes, 20 * 21);
+    drop(storage);
+
+    // Each transaction chunk is 21 bytes long, so the third transaction triggers compaction:
+    let mut storage = FileStorageOptions::new().compaction(LogSizeLimit(42)).open(&path).unwrap();
+    storage.load().unwrap();
+    assert_eq!(storage.compaction_stats().snapshot_bytes, 0);
+    storage.store_object(&object).unwrap();
+    assert_eq!(storage.compaction_stats().snapshot_bytes, 20);
+    for i in 20u8..23 {
+        let transaction = TransactionAdd(i);
+        transaction.apply(&mut object);
+        storage.store_data(&object, &transaction).unwrap();
+    }
+    assert_eq!(storage.compaction_stats().transaction_count, 0);
+    assert_eq!(storage.compaction_stats().snapshot_bytes, 23);
+}
+
+#[test]
+fn exclusive_lock() {
+    let temp_dir = temp_dir();
+    let storage = file_storage(&temp_dir);
+    match FileStorage::<Object>::new(temp_dir.path().join("test.db")) {
+        Err(Error::Locked) => (),
+        _ => unreachable!(),
+    }
+    match shared_file_storage(&temp_dir) {
+        Err(Error::Locked) => (),
+        _ => unreachable!(),
+    }
+    drop(storage);
+    file_storage(&temp_dir);
+}
+
+#[test]
+fn shared_lock() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test.db");
+    let data = with_header(&[
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 145, 47, 63, 203, 03, 04, 245, 80, 67,
+        75, 05, 00, 00
     ]);
+    write_bytes(path.clone(), &data);
+
+    let mut first = shared_file_storage(&temp_dir).unwrap();
+    let mut second = shared_file_storage(&temp_dir).unwrap();
+    assert!(first.is_read_only());
+    match FileStorage::<Object>::new(&path) {
+        Err(Error::Locked) => (),
+        _ => unreachable!(),
+    }
+
+    // The torn tail is ignored, but not truncated:
+    assert_eq!(first.load().unwrap().unwrap().0, PackedObject(vec![3, 4]));
+    assert_eq!(first.discarded_bytes(), 7);
+    assert_eq!(second.load().unwrap().unwrap().0, PackedObject(vec![3, 4]));
+    assert_eq!(read_bytes(&path), data);
+
+    match first.store_object(&Object::default()) {
+        Err(Error::ReadOnly) => (),
+        _ => unreachable!(),
+    }
+    match first.store_data(&Object::default(), &TransactionAdd(1)) {
+        Err(Error::ReadOnly) => (),
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn read_only_while_writing() {
+    let temp_dir = temp_dir();
+    let mut writer = file_storage(&temp_dir);
+    let mut reader = FileStorage::<Object>::open_read_only(writer.path()).unwrap();
+    assert!(reader.is_read_only());
+    assert_eq!(reader.load().unwrap(), None);
+
+    let mut object = Object::default();
+    writer.store_object(&object).unwrap();
+    for i in 0u8..20 {
+        let transaction = TransactionAdd(i);
+        transaction.apply(&mut object);
+        writer.store_data(&object, &transaction).unwrap();
+
+        // Every load sees exactly what has been stored so far, through appends and compactions:
+        let (packed, transactions) = reader.load().unwrap().unwrap();
+        assert_eq!(packed.0.len() + transactions.len(), i as usize + 1);
+    }
+
+    match reader.store_object(&object) {
+        Err(Error::ReadOnly) => (),
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn read_only_never_writes() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test.db");
+    let temp_path = temp_dir.path().join("test.db~");
+    let data = with_header(&[
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 145, 47, 63, 203, 03, 04
+    ]);
+    write_bytes(temp_path.clone(), &data);
+
+    let mut storage = FileStorage::<Object>::open_read_only(&path).unwrap();
+    assert_eq!(storage.load().unwrap(), None);
+    assert_eq!(read_bytes(&temp_path), data);
+    assert!(fs::metadata(&path).is_err());
+    assert!(fs::metadata(temp_dir.path().join("test.db.lock")).is_err());
+}
+
+#[test]
+fn upgrades_earlier_format_version() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test.db");
+    let mut data = with_header(&[
+        02u8, 00, 00, 00, 197, 80, 31, 11, 01, 02, 05, 00, 00, 00, 235, 41, 247, 08, 01, 00, 00,
+        00, 03
+    ]);
+    data[8] = 1;
+    write_bytes(path.clone(), &data);
+
+    let mut storage = file_storage(&temp_dir);
+    let result = storage.load().unwrap().unwrap();
+    assert_eq!(result.1, vec![PackedTransaction(1, vec![3])]);
+
+    // The file is rewritten in the current format before anything is appended to it:
+    let object = Object(vec![1, 2, 3].iter().cloned().collect());
+    storage.store_batch(&object, &[PackedTransaction(1, vec![3])]).unwrap();
+    assert_eq!(read_bytes(&path), with_header(&[
+        245u8, 80, 67, 75, 03, 00, 00, 00, 00, 00, 00, 00, 226, 142, 152, 88, 01, 02, 03
+    ]));
+}
+
+#[test]
+fn loads_narrow_lengths_of_earlier_format_version() {
+    let mut data = with_header(&[
+        02u8, 00, 00, 00, 197, 80, 31, 11, 01, 02, 22, 00, 00, 00, 181, 203, 207, 177, 255, 255,
+        255, 255, 05, 00, 00, 00, 01, 00, 00, 00, 07, 05, 00, 00, 00, 02, 00, 00, 00, 01, 05, 00,
+        00
+    ]);
+    data[8] = 2;
+    let result = write_raw_and_load(&data).unwrap().unwrap();
+    assert_eq!(result.0, PackedObject(vec![1, 2]));
+    assert_eq!(result.1, vec![PackedTransaction(1, vec![7]), PackedTransaction(2, vec![1])]);
+}
+
+#[test]
+fn verifies_file() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test.db");
+    write_bytes(path.clone(), &with_header(&[
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 145, 47, 63, 203, 03, 04, 245, 80, 67,
+        75, 05, 00, 00, 00, 00, 00, 00, 00, 174, 40, 81, 95, 01, 00, 00, 00, 05, 245, 80, 67, 75,
+        05, 00, 00, 00, 00, 00, 00, 00, 232, 98, 246, 111, 02, 00, 00, 00, 04
+    ]));
+
+    let report = FileStorage::<Object>::verify(&path).unwrap();
+    assert!(report.is_clean());
+    assert_eq!(report.file_bytes, 16 + 60);
+    assert_eq!(report.version, Some(3));
+    assert_eq!(report.snapshot_bytes, Some(2));
+    assert_eq!(report.transaction_count, 2);
+    assert_eq!(report.transaction_keys, vec![(1, 1), (2, 1)].into_iter().collect());
+    assert_eq!(report.valid_bytes, 16 + 60);
+    assert!(report.replay.is_none());
+
+    write_bytes(temp_dir.path().join("test.db~"), &[]);
+    let report = FileStorage::<Object>::verify(&path).unwrap();
+    assert!(report.temp_file);
+    assert!(!report.is_clean());
+}
+
+#[test]
+fn verifies_with_maximum_chunk_size() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test.db");
+    write_bytes(path.clone(), &with_header(&[
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 145, 47, 63, 203, 03, 04, 245, 80, 67,
+        75, 05, 00, 00, 00, 00, 00, 00, 00, 174, 40, 81, 95, 01, 00, 00, 00, 05
+    ]));
+
+    let options = FileStorageOptions::new().max_chunk_size(4);
+    let report = options.verify::<Object, _>(&path).unwrap();
+    assert_eq!(report.snapshot_bytes, Some(2));
+    let bad_chunk = BadChunk { offset: 16 + 18, fault: ChunkFault::TooLarge(5) };
+    assert_eq!(report.bad_chunk, Some(bad_chunk));
+    assert_eq!(options.scan(&path).unwrap().bad_chunk, report.bad_chunk);
+
+    let report = FileStorageOptions::new().max_chunk_size(5).verify::<Object, _>(&path).unwrap();
+    assert!(report.is_clean());
+}
+
+#[test]
+fn verifies_legacy_file() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test.db");
+    write_bytes(path.clone(), &[02u8, 00, 00, 00, 03, 04, 05, 00, 00, 00, 01, 00, 00, 00, 05]);
+
+    let report = FileStorage::<Object>::verify(&path).unwrap();
+    assert!(report.is_clean());
+    assert_eq!(report.version, Some(0));
+    assert_eq!(report.snapshot_bytes, Some(2));
+    assert_eq!(report.transaction_count, 1);
+    assert_eq!(report.valid_bytes, 15);
+}
+
+#[test]
+fn verifies_corrupt_file() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test.db");
+    let mut data = with_header(&[
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 145, 47, 63, 203, 03, 04, 245, 80, 67,
+        75, 05, 00, 00, 00, 00, 00, 00, 00, 174, 40, 81, 95, 01, 00, 00, 00, 05, 245, 80, 67, 75,
+        05, 00, 00, 00, 00, 00, 00, 00, 232, 98, 246, 111, 02, 00, 00, 00, 04
+    ]);
+    data[75] = 5;
+    write_bytes(path.clone(), &data);
+
+    let report = FileStorage::<Object>::verify(&path).unwrap();
+    assert!(!report.is_clean());
+    assert_eq!(report.transaction_count, 1);
+    assert_eq!(report.valid_bytes, 16 + 39);
+    assert_eq!(report.bad_chunk, Some(BadChunk { offset: 16 + 39, fault: ChunkFault::Checksum }));
+
+    // Verification never truncates the file:
+    assert_eq!(read_bytes(&path), data);
+    fs::remove_file(&path).unwrap();
+    write_bytes(path.clone(), &data[..72]);
+    let report = FileStorage::<Object>::verify(&path).unwrap();
+    assert_eq!(report.bad_chunk, Some(BadChunk { offset: 16 + 39, fault: ChunkFault::Truncated }));
+}
+
+#[test]
+fn verifies_replay() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test.db");
+    write_bytes(path.clone(), &with_header(&[
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 145, 47, 63, 203, 03, 04, 245, 80, 67,
+        75, 05, 00, 00, 00, 00, 00, 00, 00, 174, 40, 81, 95, 01, 00, 00, 00, 05, 245, 80, 67, 75,
+        05, 00, 00, 00, 00, 00, 00, 00, 232, 98, 246, 111, 02, 00, 00, 00, 04
+    ]));
+
+    let transactions = Transactions::new().register::<TransactionAdd>();
+    let report = FileStorage::verify_replay(&path, &transactions).unwrap();
+    match report.replay {
+        Some(Err(Error::TransactionUnregistered)) => (),
+        _ => unreachable!(),
+    }
+    assert!(!report.is_clean());
+
+    let transactions = transactions.register::<TransactionRemove>();
+    let report = FileStorage::verify_replay(&path, &transactions).unwrap();
+    assert!(report.replay.unwrap().is_ok());
+}
+
+#[test]
+fn salvages_corrupt_file() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test.db");
+    let mut data = with_header(&[
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 145, 47, 63, 203, 03, 04, 245, 80, 67,
+        75, 05, 00, 00, 00, 00, 00, 00, 00, 174, 40, 81, 95, 01, 00, 00, 00, 05, 245, 80, 67, 75,
+        05, 00, 00, 00, 00, 00, 00, 00, 232, 98, 246, 111, 02, 00, 00, 00, 04, 245, 80, 67, 75, 05,
+        00, 00, 00, 00, 00, 00, 00, 174, 40, 81, 95, 01, 00, 00, 00, 05
+    ]);
+    data[75] = 5;
+    write_bytes(path.clone(), &data);
+
+    let salvage = Salvage::recover(&path).unwrap();
+    assert_eq!(salvage.snapshot_bytes, 2);
+    assert_eq!(salvage.transaction_count, 2);
+    assert_eq!(salvage.skipped, vec![
+        SkippedRange { offset: 16 + 39, length: 21, fault: ChunkFault::Checksum },
+    ]);
+    assert_eq!(read_bytes(&path), data);
+    assert_eq!(read_bytes(&salvage.report_path), salvage.to_string().into_bytes());
+
+    let result = FileStorage::<Object>::new(&salvage.recovered_path).unwrap().load().unwrap()
+        .unwrap();
+    assert_eq!(result.0, PackedObject(vec![3, 4]));
+    assert_eq!(result.1, vec![PackedTransaction(1, vec![5]), PackedTransaction(1, vec![5])]);
+
+    data[16] = 9;
+    fs::remove_file(&path).unwrap();
+    write_bytes(path.clone(), &data);
+    match Salvage::recover(&path) {
+        Err(Error::Corrupt) => (),
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn salvages_at_next_chunk_marker() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test.db");
+    let mut data = with_header(&[
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 145, 47, 63, 203, 03, 04, 245, 80, 67,
+        75, 05, 00, 00, 00, 00, 00, 00, 00, 174, 40, 81, 95, 01, 00, 00, 00, 05, 245, 80, 67, 75,
+        05, 00, 00, 00, 00, 00, 00, 00, 232, 98, 246, 111, 02, 00, 00, 00, 04, 245, 80, 67, 75, 05,
+        00, 00, 00, 00, 00, 00, 00, 174, 40, 81, 95, 01, 00, 00, 00, 05
+    ]);
+    // The damaged length hides where the chunk ends:
+    data[16 + 22] = 200;
+    write_bytes(path.clone(), &data);
+
+    let salvage = Salvage::recover(&path).unwrap();
+    assert_eq!(salvage.transaction_count, 2);
+    assert_eq!(salvage.skipped, vec![
+        SkippedRange { offset: 16 + 18, length: 21, fault: ChunkFault::Truncated },
+    ]);
+
+    // Chunks larger than the configured maximum are skipped as well:
+    let salvage = FileStorageOptions::new().max_chunk_size(4).salvage(&path).unwrap();
+    assert_eq!(salvage.snapshot_bytes, 2);
+    assert_eq!(salvage.transaction_count, 0);
+    assert_eq!(salvage.skipped, vec![
+        SkippedRange { offset: 16 + 18, length: 63, fault: ChunkFault::Truncated },
+    ]);
+}
+
+#[test]
+fn salvages_past_chunk_within_payload() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test.db");
+    let mut data = with_header(&[
+        245u8, 80, 67, 75, 02, 00, 00, 00, 00, 00, 00, 00, 145, 47, 63, 203, 03, 04, 245, 80, 67,
+        75, 25, 00, 00, 00, 00, 00, 00, 00, 72, 247, 99, 211, 01, 00, 00, 00, 245, 80, 67, 75, 05,
+        00, 00, 00, 00, 00, 00, 00, 232, 98, 246, 111, 02, 00, 00, 00, 04, 245, 80, 67, 75, 05, 00,
+        00, 00, 00, 00, 00, 00, 174, 40, 81, 95, 01, 00, 00, 00, 05
+    ]);
+    data[16 + 34] = 9;
+    write_bytes(path.clone(), &data);
+
+    // The chunk held by the damaged transaction's payload is not mistaken for a transaction:
+    let salvage = Salvage::recover(&path).unwrap();
+    assert_eq!(salvage.skipped, vec![
+        SkippedRange { offset: 16 + 18, length: 41, fault: ChunkFault::Checksum },
+    ]);
+    let result = FileStorage::<Object>::new(&salvage.recovered_path).unwrap().load().unwrap()
+        .unwrap();
+    assert_eq!(result.1, vec![PackedTransaction(1, vec![5])]);
+}
+
+#[test]
+fn loads_legacy_format() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test.db");
+    write_bytes(path.clone(), &[02u8, 00, 00, 00, 03, 04, 05, 00, 00, 00, 01, 00, 00, 00, 05]);
+
+    let mut protium = Protium::new(file_storage(&temp_dir), transactions()).unwrap();
+    assert_eq!(*protium.object(), Object(vec![3, 4, 5].iter().cloned().collect()));
+
+    // The file is rewritten in the current format before anything is appended to it:
+    protium.apply(TransactionAdd(6)).unwrap();
+    drop(protium);
+    assert_eq!(read_bytes(&path)[..16], HEADER);
+    let result = file_storage(&temp_dir).load().unwrap().unwrap();
+    assert_eq!(result.0, PackedObject(vec![3, 4, 5, 6]));
+    assert_eq!(result.1, vec![]);
+}
+
+#[test]
+fn keeps_file_with_zeroed_header() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test.db");
+    let mut storage = FileStorageOptions::new().compaction(Never).open(&path).unwrap();
+    let mut object = Object::default();
+    storage.store_object(&object).unwrap();
+    for i in 0u8..50 {
+        let transaction = TransactionAdd(i);
+        transaction.apply(&mut object);
+        storage.store_data(&object, &transaction).unwrap();
+    }
+    drop(storage);
+
+    let mut data = read_bytes(&path);
+    for byte in &mut data[..16] {
+        *byte = 0;
+    }
+    fs::remove_file(&path).unwrap();
+    write_bytes(path.clone(), &data);
+
+    // The zeroed bytes parse as the start of a legacy file, but the rest of the file does not:
+    match FileStorage::<Object>::new(&path) {
+        Err(Error::InvalidHeader) => (),
+        _ => unreachable!(),
+    }
+    assert_eq!(read_bytes(&path), data);
+}
+
+#[test]
+fn keeps_legacy_file_with_invalid_tail() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test.db");
+    let data = [02u8, 00, 00, 00, 03, 04, 06, 00, 00, 00, 255, 255, 255, 255, 09, 00];
+    write_bytes(path.clone(), &data);
+
+    match file_storage(&temp_dir).load() {
+        Err(Error::Corrupt) => (),
+        _ => unreachable!(),
+    }
+    assert_eq!(read_bytes(&path), data);
+}
+
+#[test]
+fn keeps_torn_tail_of_object_that_fails_to_unpack() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test.db");
+    let data = with_header(&[
+        245u8, 80, 67, 75, 01, 00, 00, 00, 00, 00, 00, 00, 96, 239, 112, 220, 255, 245, 80, 67, 75,
+        05, 00, 00
+    ]);
+    write_bytes(path.clone(), &data);
+
+    match file_storage(&temp_dir).load() {
+        Err(Error::Corrupt) => (),
+        _ => unreachable!(),
+    }
+    assert_eq!(read_bytes(&path), data);
+}
+
+#[test]
+fn rejects_torn_header() {
+    assert_eq!(write_raw_and_load(&[]).unwrap(), None);
+
+    for length in &[5, 12] {
+        match write_raw_and_load(&HEADER[..*length]) {
+            Err(Error::Corrupt) => (),
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[test]
+fn rejects_invalid_header() {
+    // Neither a header nor a complete chunk of the legacy format:
+    match write_raw_and_load(&[09u8, 00, 00, 00, 03, 04]) {
+        Err(Error::InvalidHeader) => (),
+        _ => unreachable!(),
+    }
+
+    match write_raw_and_load(b"PROTEIN") {
+        Err(Error::InvalidHeader) => (),
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn rejects_unsupported_format() {
+    let mut header = HEADER;
+    header[8] = 4;
+    match write_raw_and_load(&header) {
+        Err(Error::UnsupportedVersion(4)) => (),
+        _ => unreachable!(),
+    }
+
+    let mut header = HEADER;
+    header[12] = 1;
+    match write_raw_and_load(&header) {
+        Err(Error::UnsupportedFlags(1)) => (),
+        _ => unreachable!(),
+    }
 }
 
 fn write_and_load(data: &[u8], temp: bool)
@@ -97,19 +858,45 @@ fn write_and_load(data: &[u8], temp: bool)
         false => "test.db",
         true => "test.db~",
     });
-    write_bytes(path, data);
+    write_bytes(path, &with_header(data));
 
     file_storage(&temp_dir).load()
 }
 
+fn write_raw_and_load(data: &[u8])
+    -> Result<Option<(PackedObject, Vec<PackedTransaction>)>, Error>
+{
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test.db");
+    write_bytes(path.clone(), data);
+
+    try!(FileStorage::<Object>::new(path)).load()
+}
+
+fn with_header(data: &[u8]) -> Vec<u8> {
+    let mut result = HEADER.to_vec();
+    result.extend(data.iter().cloned());
+    result
+}
+
 fn file_storage(temp_dir: &TempDir) -> FileStorage<Object> {
     FileStorage::<Object>::new(temp_dir.path().join("test.db")).unwrap()
 }
 
+fn shared_file_storage(temp_dir: &TempDir) -> Result<FileStorage<Object>, Error> {
+    FileStorageOptions::new().lock(LockMode::Shared).open(temp_dir.path().join("test.db"))
+}
+
 fn temp_dir() -> TempDir {
     TempDir::new("protium").unwrap()
 }
 
+fn read_bytes(path: &Path) -> Vec<u8> {
+    let mut result = vec![];
+    File::open(path).unwrap().read_to_end(&mut result).unwrap();
+    result
+}
+
 fn write_bytes(path: PathBuf, data: &[u8]) {
     OpenOptions::new().write(true).create(true).open(path).unwrap().write_all(data).unwrap();
 }
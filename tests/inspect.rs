@@ -0,0 +1,62 @@
+use common::Object;
+use protium::{FileStorage, PackedTransaction, Storage};
+use std::path::Path;
+use std::process::Command;
+use tempdir::TempDir;
+
+#[test]
+fn prints_human_readable_layout() {
+    let temp_dir = TempDir::new("protium").unwrap();
+    let path = write_storage(temp_dir.path());
+    let output = inspect(&["--hex", path.to_str().unwrap()]);
+    let lines: Vec<_> = output.lines().skip(6).collect();
+    assert_eq!(lines, vec![
+        "      OFFSET  KIND                 KEY      LENGTH",
+        "          16  object                 -           2  0102",
+        "          54  transaction            1           1  07",
+        "          67  transaction            2           1  01",
+    ]);
+}
+
+#[test]
+fn reads_with_maximum_chunk_size() {
+    let temp_dir = TempDir::new("protium").unwrap();
+    let path = write_storage(temp_dir.path());
+    let output = inspect(&["--max-chunk-size=1", path.to_str().unwrap()]);
+    // There is no snapshot bytes line, since the object is invalid.
+    let lines: Vec<_> = output.lines().skip(5).collect();
+    assert_eq!(lines, vec![
+        "      OFFSET  KIND                 KEY      LENGTH",
+        "          16  bad chunk     too large (2 bytes)",
+    ]);
+}
+
+#[test]
+fn prints_json_layout() {
+    let temp_dir = TempDir::new("protium").unwrap();
+    let path = write_storage(temp_dir.path());
+    let output = inspect(&["--json", "--base64", path.to_str().unwrap()]);
+    assert_eq!(output.trim_end(), format!(
+        "{{\"path\":\"{}\",\"version\":3,\"file_bytes\":80,\"valid_bytes\":80,\
+         \"snapshot_bytes\":2,\"records\":[\
+         {{\"offset\":16,\"kind\":\"object\",\"length\":2,\"payload\":\"AQI=\"}},\
+         {{\"offset\":54,\"kind\":\"transaction\",\"key\":1,\"length\":1,\"payload\":\"Bw==\"}},\
+         {{\"offset\":67,\"kind\":\"transaction\",\"key\":2,\"length\":1,\"payload\":\"AQ==\"}}],\
+         \"bad_chunk\":null}}", path.display()));
+}
+
+fn write_storage(directory: &Path) -> ::std::path::PathBuf {
+    let path = directory.join("test.db");
+    let mut storage = FileStorage::new(&path).unwrap();
+    let object = Object(vec![1, 2].iter().cloned().collect());
+    storage.store_object(&object).unwrap();
+    let batch = vec![PackedTransaction(1, vec![7]), PackedTransaction(2, vec![1])];
+    storage.store_batch(&object, &batch).unwrap();
+    path
+}
+
+fn inspect(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_protium-inspect")).args(args).output().unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
@@ -0,0 +1,60 @@
+use common::{transactions, Object, TransactionAdd, TransactionRemove};
+use protium::compaction::{LogSizeLimit, Never};
+use protium::{MemoryStorage, PackedObject, PackedTransaction, Protium, Storage, Transaction};
+
+#[test]
+fn compaction_policy() {
+    let mut storage = MemoryStorage::new().compaction(Never);
+    let mut object = Object::default();
+    storage.store_object(&object).unwrap();
+    for i in 0u8..20 {
+        let transaction = TransactionAdd(i);
+        transaction.apply(&mut object);
+        storage.store_data(&object, &transaction).unwrap();
+    }
+    assert_eq!(storage.compaction_stats().transaction_count, 20);
+    assert_eq!(storage.compaction_stats().log_bytes, 20 * 21);
+
+    // The log is measured as a `FileStorage` would measure it, so the third transaction triggers
+    // compaction here too:
+    let mut storage = MemoryStorage::new().compaction(LogSizeLimit(42));
+    storage.store_object(&object).unwrap();
+    assert_eq!(storage.compaction_stats().snapshot_bytes, 20);
+    for i in 20u8..23 {
+        let transaction = TransactionAdd(i);
+        transaction.apply(&mut object);
+        storage.store_data(&object, &transaction).unwrap();
+    }
+    assert_eq!(storage.compaction_stats().transaction_count, 0);
+    assert_eq!(storage.object(), Some(&PackedObject((0u8..23).collect())));
+}
+
+#[test]
+fn measures_batch_as_file_storage() {
+    let mut storage = MemoryStorage::new().compaction(Never);
+    let object = Object::default();
+    storage.store_object(&object).unwrap();
+    let batch = vec![PackedTransaction(1, vec![7]), PackedTransaction(2, vec![1, 2])];
+    storage.store_batch(&object, &batch).unwrap();
+
+    // The chunk header, the batch key, then the length, key and data of each transaction:
+    assert_eq!(storage.compaction_stats().log_bytes, 16 + 4 + 13 + 14);
+}
+
+#[test]
+fn clone_simulates_restart() {
+    let mut protium = Protium::new(MemoryStorage::new(), transactions()).unwrap();
+    protium.apply(TransactionAdd(5)).unwrap();
+    protium.apply(TransactionAdd(10)).unwrap();
+    protium.apply(TransactionRemove(5)).unwrap();
+    assert_eq!(protium.storage().transactions(), &[
+        PackedTransaction(1, vec![5]), PackedTransaction(1, vec![10]), PackedTransaction(2, vec![5])
+    ]);
+
+    let restarted = Protium::new(protium.storage().clone(), transactions()).unwrap();
+    assert_eq!(restarted.object(), protium.object());
+
+    // The clone is independent of the original:
+    protium.apply(TransactionAdd(15)).unwrap();
+    assert_eq!(restarted.storage().transactions().len(), 3);
+}
@@ -0,0 +1,203 @@
+use common::{transactions, Object, TransactionAdd, TransactionRemove};
+use protium::compaction::{Never, TransactionLimit};
+use protium::{
+    Error, LockMode, PackedObject, PackedTransaction, Protium, SegmentedStorage,
+    SegmentedStorageOptions, Storage, SyncMode, Transaction
+};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use tempdir::TempDir;
+
+#[test]
+fn reloads_object() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test");
+    let mut protium = Protium::new(SegmentedStorage::new(&path).unwrap(), transactions()).unwrap();
+    for i in 0u8..40 {
+        protium.apply(TransactionAdd(i)).unwrap();
+    }
+    protium.apply(TransactionRemove(20)).unwrap();
+    let expected: Object = Object((0u8..40).filter(|&i| i != 20).collect());
+    assert_eq!(*protium.object(), expected);
+    drop(protium);
+
+    let protium = Protium::new(SegmentedStorage::new(&path).unwrap(), transactions()).unwrap();
+    assert_eq!(*protium.object(), expected);
+}
+
+#[test]
+fn stores_batches() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test");
+    let mut protium = Protium::new(SegmentedStorage::new(&path).unwrap(), transactions()).unwrap();
+    {
+        let mut batch = protium.batch();
+        for i in 0u8..40 {
+            batch.apply(TransactionAdd(i)).unwrap();
+        }
+        batch.commit().unwrap();
+    }
+    assert_eq!(protium.storage().compaction_stats().transaction_count, 40);
+    drop(protium);
+
+    let protium = Protium::new(SegmentedStorage::new(&path).unwrap(), transactions()).unwrap();
+    assert_eq!(*protium.object(), Object((0u8..40).collect()));
+}
+
+#[test]
+fn flushes_without_syncing() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test");
+    let storage = options().sync(SyncMode::None).segment_size(64).open(&path).unwrap();
+    let mut protium = Protium::new(storage, transactions()).unwrap();
+    for i in 0u8..40 {
+        protium.apply(TransactionAdd(i)).unwrap();
+    }
+    protium.flush().unwrap();
+    drop(protium);
+
+    let protium = Protium::new(SegmentedStorage::new(&path).unwrap(), transactions()).unwrap();
+    assert_eq!(*protium.object(), Object((0u8..40).collect()));
+}
+
+#[test]
+fn rolls_over_log_segments() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test");
+    let mut storage = options().compaction(Never).segment_size(16 + 2 * 17).open(&path).unwrap();
+    let mut object = Object::default();
+    storage.store_object(&object).unwrap();
+    for i in 0u8..5 {
+        let transaction = TransactionAdd(i);
+        transaction.apply(&mut object);
+        storage.store_data(&object, &transaction).unwrap();
+    }
+    assert_eq!(file_names(&path), vec![
+        "LOCK", "MANIFEST", "log-00000000000000000002", "log-00000000000000000003",
+        "log-00000000000000000004", "snapshot-00000000000000000001"
+    ]);
+    drop(storage);
+
+    let mut storage = SegmentedStorage::<Object>::new(&path).unwrap();
+    let (object, transactions) = storage.load().unwrap().unwrap();
+    assert_eq!(object, PackedObject(vec![]));
+    assert_eq!(transactions.len(), 5);
+    assert_eq!(transactions[4], PackedTransaction(1, vec![4]));
+}
+
+#[test]
+fn compaction_removes_old_files() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test");
+    let mut storage = options().compaction(TransactionLimit(3)).segment_size(0).open(&path)
+        .unwrap();
+    let mut object = Object::default();
+    storage.store_object(&object).unwrap();
+    for i in 0u8..4 {
+        let transaction = TransactionAdd(i);
+        transaction.apply(&mut object);
+        storage.store_data(&object, &transaction).unwrap();
+    }
+    assert_eq!(file_names(&path), vec![
+        "LOCK", "MANIFEST", "snapshot-00000000000000000002"
+    ]);
+    assert_eq!(storage.load().unwrap().unwrap(), (PackedObject(vec![0, 1, 2, 3]), vec![]));
+}
+
+#[test]
+fn compaction_archives_old_files() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test");
+    let archive = temp_dir.path().join("archive");
+    let mut storage = options().compaction(TransactionLimit(2)).archive(&archive).open(&path)
+        .unwrap();
+    let mut object = Object::default();
+    storage.store_object(&object).unwrap();
+    for i in 0u8..3 {
+        let transaction = TransactionAdd(i);
+        transaction.apply(&mut object);
+        storage.store_data(&object, &transaction).unwrap();
+    }
+    assert_eq!(file_names(&path), vec!["LOCK", "MANIFEST", "snapshot-00000000000000000002"]);
+    assert_eq!(file_names(&archive), vec![
+        "log-00000000000000000002", "snapshot-00000000000000000001"
+    ]);
+}
+
+#[test]
+fn truncates_torn_tail_on_load() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test");
+    let mut storage = options().compaction(Never).segment_size(16 + 21).open(&path).unwrap();
+    let mut object = Object::default();
+    storage.store_object(&object).unwrap();
+    for i in 0u8..2 {
+        let transaction = TransactionAdd(i);
+        transaction.apply(&mut object);
+        storage.store_data(&object, &transaction).unwrap();
+    }
+    drop(storage);
+
+    let segment = path.join("log-00000000000000000002");
+    OpenOptions::new().append(true).open(&segment).unwrap().write_all(&[13, 0, 0]).unwrap();
+    let later = path.join("log-00000000000000000003");
+    OpenOptions::new().append(true).open(&later).unwrap().write_all(&[1, 2, 3]).unwrap();
+
+    let mut storage = SegmentedStorage::<Object>::new(&path).unwrap();
+    let (_, transactions) = storage.load().unwrap().unwrap();
+    assert_eq!(transactions, vec![PackedTransaction(1, vec![0])]);
+    assert_eq!(storage.discarded_bytes(), 3 + 16 + 21 + 3);
+    assert_eq!(fs::metadata(&segment).unwrap().len(), 16 + 21);
+    assert!(fs::metadata(&later).is_err());
+}
+
+#[test]
+fn removes_files_of_interrupted_compaction() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test");
+    let mut storage = SegmentedStorage::<Object>::new(&path).unwrap();
+    storage.store_object(&Object::default()).unwrap();
+    drop(storage);
+
+    fs::copy(path.join("snapshot-00000000000000000001"), path.join("snapshot-00000000000000000002"))
+        .unwrap();
+    fs::copy(path.join("MANIFEST"), path.join("MANIFEST~")).unwrap();
+    SegmentedStorage::<Object>::new(&path).unwrap();
+    assert_eq!(file_names(&path), vec!["LOCK", "MANIFEST", "snapshot-00000000000000000001"]);
+}
+
+#[test]
+fn locks_directory() {
+    let temp_dir = temp_dir();
+    let path = temp_dir.path().join("test");
+    let mut storage = SegmentedStorage::<Object>::new(&path).unwrap();
+    storage.store_object(&Object::default()).unwrap();
+    match SegmentedStorage::<Object>::new(&path) {
+        Err(Error::Locked) => (),
+        _ => unreachable!(),
+    }
+
+    let mut reader = options().lock(LockMode::Unlocked).open::<Object, _>(&path).unwrap();
+    assert_eq!(reader.load().unwrap().unwrap().0, PackedObject(vec![]));
+    match reader.store_object(&Object::default()) {
+        Err(Error::ReadOnly) => (),
+        _ => unreachable!(),
+    }
+}
+
+fn file_names(path: &Path) -> Vec<String> {
+    let mut result: Vec<String> = fs::read_dir(path).unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    result.sort();
+    result
+}
+
+fn options() -> SegmentedStorageOptions {
+    SegmentedStorageOptions::new()
+}
+
+fn temp_dir() -> TempDir {
+    TempDir::new("protium").unwrap()
+}
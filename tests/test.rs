@@ -2,10 +2,27 @@ extern crate protium;
 extern crate tempdir;
 
 mod common;
+mod compaction;
+mod crash;
+mod faulty_storage;
 mod file_storage;
+mod inspect;
+mod memory_storage;
+mod segmented_storage;
 
-use common::{Object, SimpleStorage, TransactionAdd, TransactionRemove};
-use protium::{Protium, Storage, Transactions};
+use common::{
+    transactions, Object, TransactionAdd, TransactionAddAll, TransactionPanic, TransactionRemove
+};
+use protium::{
+    Error, FaultyStorage, FileStorage, MemoryStorage, PackedObject, PackedTransaction, Protium,
+    RecoveryReport, Storage, StorageOperation, TransactionKey
+};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::fs::OpenOptions;
+use std::io::{self, ErrorKind, Write};
+use std::rc::Rc;
+use tempdir::TempDir;
 
 #[test]
 fn empty_storage_is_default() {
@@ -22,13 +39,204 @@ fn add_to_empty_storage() {
     protium.apply(TransactionRemove(10)).unwrap();
     assert_eq!(*protium.object(), Object(vec![5, 15].iter().cloned().collect()));
     let storage_transactions = vec![(1, vec![5]), (1, vec![10]), (1, vec![15]), (2, vec![10])];
-    assert_eq!(*protium.storage(), SimpleStorage::new(Some(vec![]), storage_transactions));
+    assert_stored(protium.storage(), vec![], storage_transactions);
+}
+
+#[test]
+fn returns_transaction_output() {
+    let mut protium = Protium::new(empty_storage(), transactions()).unwrap();
+    assert!(protium.apply(TransactionAdd(5)).unwrap());
+    assert!(!protium.apply(TransactionAdd(5)).unwrap());
+    protium.apply(TransactionRemove(5)).unwrap();
+    assert_stored(protium.storage(), vec![], vec![(1, vec![5]), (1, vec![5]), (2, vec![5])]);
+}
+
+#[test]
+fn apply_batch() {
+    let mut protium = Protium::new(empty_storage(), transactions()).unwrap();
+    protium.apply(TransactionAdd(5)).unwrap();
+    {
+        let mut batch = protium.batch();
+        batch.apply(TransactionAdd(10)).unwrap();
+        batch.apply(TransactionRemove(5)).unwrap();
+        assert_eq!(batch.len(), 2);
+        batch.commit().unwrap();
+    }
+    assert_eq!(*protium.object(), Object(vec![10].iter().cloned().collect()));
+    let storage_transactions = vec![(1, vec![5]), (1, vec![10]), (2, vec![5])];
+    assert_stored(protium.storage(), vec![], storage_transactions);
+}
+
+#[test]
+fn batch_borrowed_transaction() {
+    let values = vec![3, 4];
+    let transactions = transactions().register::<TransactionAddAll>();
+    let mut protium = Protium::new(empty_storage(), transactions).unwrap();
+    {
+        let mut batch = protium.batch();
+        batch.apply(TransactionAddAll(Cow::Borrowed(&values))).unwrap();
+        batch.commit().unwrap();
+    }
+    assert_eq!(*protium.object(), Object(vec![3, 4].iter().cloned().collect()));
+    assert_stored(protium.storage(), vec![], vec![(4, vec![3, 4])]);
+}
+
+#[test]
+fn abandoned_batch() {
+    let mut protium = Protium::new(empty_storage(), transactions()).unwrap();
+    {
+        let mut batch = protium.batch();
+        batch.apply(TransactionAdd(10)).unwrap();
+        match batch.apply(TransactionAdd(255)) {
+            Err(protium::Error::TransactionPack) => (),
+            _ => unreachable!(),
+        }
+        assert_eq!(batch.len(), 1);
+    }
+    assert_eq!(*protium.object(), Object::default());
+    assert_stored(protium.storage(), vec![], vec![]);
+}
+
+#[test]
+fn rejects_transaction() {
+    let mut protium = Protium::new(empty_storage(), transactions()).unwrap();
+    protium.apply(TransactionAdd(5)).unwrap();
+    match protium.apply(TransactionRemove(10)) {
+        Err(protium::Error::Rejected(ref err)) => {
+            assert_eq!(err.to_string(), "the value is absent");
+        },
+        _ => unreachable!(),
+    }
+    assert_eq!(*protium.object(), Object(vec![5].iter().cloned().collect()));
+    assert_stored(protium.storage(), vec![], vec![(1, vec![5])]);
+
+    // Rejecting the first transaction of a batch leaves the object unchanged:
+    {
+        let mut batch = protium.batch();
+        batch.apply(TransactionRemove(10)).unwrap();
+        batch.apply(TransactionAdd(10)).unwrap();
+        match batch.commit() {
+            Err(protium::Error::Rejected(_)) => (),
+            _ => unreachable!(),
+        }
+    }
+    assert!(!protium.is_poisoned());
+    assert_eq!(*protium.object(), Object(vec![5].iter().cloned().collect()));
+
+    // Each transaction of a batch is checked after those before it are applied, and rejecting a
+    // later one restores the object as it was before the batch:
+    {
+        let mut batch = protium.batch();
+        batch.apply(TransactionAdd(10)).unwrap();
+        batch.apply(TransactionRemove(10)).unwrap();
+        batch.apply(TransactionRemove(10)).unwrap();
+        match batch.commit() {
+            Err(protium::Error::Rejected(_)) => (),
+            _ => unreachable!(),
+        }
+    }
+    assert!(!protium.is_poisoned());
+    assert_eq!(*protium.object(), Object(vec![5].iter().cloned().collect()));
+    assert_stored(protium.storage(), vec![], vec![(1, vec![5])]);
+    protium.apply(TransactionAdd(6)).unwrap();
+    assert_eq!(*protium.object(), Object(vec![5, 6].iter().cloned().collect()));
+}
+
+#[test]
+fn poisons_object_on_storage_failure() {
+    let storage = FaultyStorage::new(MemoryStorage::new())
+        .fail(StorageOperation::StoreData, 2, Error::Io(io::Error::new(ErrorKind::Other, "full")))
+        .fail(StorageOperation::StoreBatch, 1, Error::Corrupt);
+    let mut protium = Protium::new(storage, transactions()).unwrap();
+    protium.apply(TransactionAdd(5)).unwrap();
+    assert!(protium.apply(TransactionAdd(10)).is_err());
+    assert!(protium.is_poisoned());
+    match protium.apply(TransactionAdd(15)) {
+        Err(Error::Poisoned) => (),
+        _ => unreachable!(),
+    }
+
+    let report = protium.reload().unwrap();
+    assert_eq!(report.replayed_transactions, 1);
+    assert!(!protium.is_poisoned());
+    assert_eq!(*protium.object(), Object(vec![5].iter().cloned().collect()));
+
+    {
+        let mut batch = protium.batch();
+        batch.apply(TransactionAdd(20)).unwrap();
+        assert!(batch.commit().is_err());
+    }
+    assert!(protium.is_poisoned());
+    protium.reload().unwrap();
+    protium.apply(TransactionAdd(25)).unwrap();
+    assert_eq!(*protium.object(), Object(vec![5, 25].iter().cloned().collect()));
+}
+
+#[test]
+fn poisons_object_on_panic() {
+    let mut protium = Protium::new(empty_storage(), transactions()).unwrap();
+    protium.apply(TransactionAdd(5)).unwrap();
+    match protium.apply(TransactionPanic(10)) {
+        Err(Error::Panicked(ref message)) => assert_eq!(message, "panicked applying 10"),
+        _ => unreachable!(),
+    }
+    assert!(protium.is_poisoned());
+    assert_eq!(*protium.object(), Object(vec![5, 10].iter().cloned().collect()));
+    assert_stored(protium.storage(), vec![], vec![(1, vec![5])]);
+
+    protium.reload().unwrap();
+    assert_eq!(*protium.object(), Object(vec![5].iter().cloned().collect()));
+
+    {
+        let mut batch = protium.batch();
+        batch.apply(TransactionAdd(15)).unwrap();
+        batch.apply(TransactionPanic(20)).unwrap();
+        match batch.commit() {
+            Err(Error::Panicked(_)) => (),
+            _ => unreachable!(),
+        }
+    }
+    assert!(protium.is_poisoned());
+    protium.reload().unwrap();
+    assert_eq!(*protium.object(), Object(vec![5].iter().cloned().collect()));
+    assert_stored(protium.storage(), vec![], vec![(1, vec![5])]);
+
+    // A transaction that panics while being replayed fails the load:
+    let storage = stored_storage(vec![5], vec![(3, vec![10])]);
+    match Protium::new(storage, transactions()) {
+        Err(Error::Panicked(_)) => (),
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn observes_stored_transactions() {
+    let mut protium = Protium::new(empty_storage(), transactions()).unwrap();
+    let observed = Rc::new(RefCell::new(vec![]));
+    let sink = observed.clone();
+    protium.observe(move |transaction: &PackedTransaction, object: &Object| {
+        sink.borrow_mut().push((transaction.clone(), object.0.len()));
+    });
+
+    protium.apply(TransactionAdd(5)).unwrap();
+    assert!(protium.apply(TransactionAdd(255)).is_err());
+    {
+        let mut batch = protium.batch();
+        batch.apply(TransactionAdd(10)).unwrap();
+        batch.apply(TransactionRemove(5)).unwrap();
+        batch.commit().unwrap();
+    }
+    assert_eq!(*observed.borrow(), vec![
+        (PackedTransaction(1, vec![5]), 1),
+        (PackedTransaction(1, vec![10]), 1),
+        (PackedTransaction(2, vec![5]), 1),
+    ]);
 }
 
 #[test]
 fn load_from_storage() {
     let storage_transactions = vec![(1, vec![10]), (1, vec![15]), (2, vec![10])];
-    let storage = SimpleStorage::new(Some(vec![5]), storage_transactions);
+    let storage = stored_storage(vec![5], storage_transactions);
     let protium = Protium::new(storage, transactions()).unwrap();
     assert_eq!(*protium.object(), Object(vec![5, 15].iter().cloned().collect()));
 }
@@ -36,7 +244,7 @@ fn load_from_storage() {
 #[test]
 fn unpacking_unregistered_transaction_keys() {
     let storage_transactions = vec![(1, vec![10]), (1000, vec![15])];
-    let storage = SimpleStorage::new(Some(vec![5]), storage_transactions);
+    let storage = stored_storage(vec![5], storage_transactions);
     match Protium::new(storage, transactions()) {
         Err(protium::Error::TransactionUnregistered) => (),
         _ => unreachable!(),
@@ -53,7 +261,7 @@ fn packing_invalid_object() {
 
 #[test]
 fn unpacking_invalid_object() {
-    let storage = SimpleStorage::new(Some(vec![255]), vec![]);
+    let storage = stored_storage(vec![255], vec![]);
     match Protium::new(storage, transactions()) {
         Err(protium::Error::ObjectUnpack) => (),
         _ => unreachable!(),
@@ -74,17 +282,72 @@ fn packing_invalid_transaction() {
 
 #[test]
 fn unpacking_invalid_transaction() {
-    let storage = SimpleStorage::new(Some(vec![1]), vec![(1, vec![1, 2])]);
+    let storage = stored_storage(vec![1], vec![(1, vec![1, 2])]);
     match Protium::new(storage, transactions()) {
         Err(protium::Error::TransactionUnpack) => (),
         _ => unreachable!(),
     }
 }
 
-fn empty_storage() -> SimpleStorage<Object> {
-    SimpleStorage::new(None, vec![])
+#[test]
+fn read_only_storage() {
+    let temp_dir = TempDir::new("protium").unwrap();
+    let path = temp_dir.path().join("test.db");
+    let mut writer = Protium::new(FileStorage::new(&path).unwrap(), transactions()).unwrap();
+    writer.apply(TransactionAdd(5)).unwrap();
+
+    let mut reader = Protium::new(FileStorage::open_read_only(&path).unwrap(), transactions())
+        .unwrap();
+    assert_eq!(*reader.object(), Object(vec![5].iter().cloned().collect()));
+    match reader.apply(TransactionAdd(10)) {
+        Err(protium::Error::ReadOnly) => (),
+        _ => unreachable!(),
+    }
+    assert_eq!(*reader.object(), Object(vec![5].iter().cloned().collect()));
+}
+
+#[test]
+fn reports_recovery() {
+    let temp_dir = TempDir::new("protium").unwrap();
+    let path = temp_dir.path().join("test.db");
+    let (mut protium, report) =
+        Protium::new_with_report(FileStorage::new(&path).unwrap(), transactions()).unwrap();
+    assert!(report.created_default);
+    assert!(report.is_clean());
+    protium.apply(TransactionAdd(5)).unwrap();
+    protium.apply(TransactionAdd(10)).unwrap();
+    drop(protium);
+
+    OpenOptions::new().append(true).open(&path).unwrap().write_all(&[1, 0, 0]).unwrap();
+    let (protium, report) =
+        Protium::new_with_report(FileStorage::new(&path).unwrap(), transactions()).unwrap();
+    assert_eq!(*protium.object(), Object(vec![5, 10].iter().cloned().collect()));
+    assert_eq!(report, RecoveryReport {
+        created_default: false,
+        promoted_temp_file: false,
+        replayed_transactions: 2,
+        discarded_bytes: 3,
+    });
+    assert!(!report.is_clean());
+}
+
+fn empty_storage() -> MemoryStorage<Object> {
+    MemoryStorage::new()
+}
+
+fn stored_storage(object: Vec<u8>, transactions: Vec<(TransactionKey, Vec<u8>)>)
+    -> MemoryStorage<Object>
+{
+    MemoryStorage::with_contents(PackedObject(object), packed_transactions(transactions))
+}
+
+fn assert_stored(storage: &MemoryStorage<Object>, object: Vec<u8>,
+                 transactions: Vec<(TransactionKey, Vec<u8>)>)
+{
+    assert_eq!(storage.object(), Some(&PackedObject(object)));
+    assert_eq!(storage.transactions(), &packed_transactions(transactions)[..]);
 }
 
-fn transactions() -> Transactions<Object> {
-    Transactions::new().register::<TransactionAdd>().register::<TransactionRemove>()
+fn packed_transactions(transactions: Vec<(TransactionKey, Vec<u8>)>) -> Vec<PackedTransaction> {
+    transactions.into_iter().map(|(key, data)| PackedTransaction(key, data)).collect()
 }